@@ -0,0 +1,29 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nordigen::fuzzing::parse_request;
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+    let lines: Vec<String> = text.split("\r\n").map(String::from).collect();
+
+    if let Ok(req) = parse_request(&lines) {
+        assert!(req.path.starts_with('/'));
+        assert!(req.method.eq_ignore_ascii_case("get"));
+    }
+});
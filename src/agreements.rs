@@ -0,0 +1,159 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Utc};
+use futures_util::stream::Stream;
+use serde::{Deserialize, Serialize};
+
+use crate::client::{NordigenClient, Page};
+use crate::error::NordigenError;
+
+/// What an end user agreement grants access to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessScope {
+    Balances,
+    Details,
+    Transactions,
+}
+
+impl AccessScope {
+    pub fn all() -> Vec<AccessScope> {
+        vec![
+            AccessScope::Balances,
+            AccessScope::Details,
+            AccessScope::Transactions,
+        ]
+    }
+}
+
+/// Terms of access to an end user's accounts at an institution. Without
+/// one, requisitions get the default of 90 days of history and access.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EndUserAgreement {
+    pub id: String,
+    pub created: DateTime<Utc>,
+    pub institution_id: String,
+    pub max_historical_days: u32,
+    pub access_valid_for_days: u32,
+    pub access_scope: Vec<AccessScope>,
+    pub accepted: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EndUserAgreementRequest {
+    pub institution_id: String,
+    pub max_historical_days: u32,
+    pub access_valid_for_days: u32,
+    pub access_scope: Vec<AccessScope>,
+}
+
+impl EndUserAgreementRequest {
+    /// Request the API's defaults: 90 days of history, 90 days of access,
+    /// and every scope.
+    pub fn new(institution_id: &str) -> EndUserAgreementRequest {
+        EndUserAgreementRequest {
+            institution_id: institution_id.to_string(),
+            max_historical_days: 90,
+            access_valid_for_days: 90,
+            access_scope: AccessScope::all(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AcceptAgreementRequest<'a> {
+    user_agent: &'a str,
+    ip_address: &'a str,
+}
+
+impl NordigenClient {
+    pub async fn create_agreement(
+        &self,
+        request: &EndUserAgreementRequest,
+    ) -> Result<EndUserAgreement, NordigenError> {
+        if request.access_scope.is_empty() {
+            return Err(NordigenError::InvalidRequest(String::from(
+                "agreement access scope must not be empty",
+            )));
+        }
+        self.json::<EndUserAgreement>(
+            self.post("agreements/enduser/").json(request),
+        )
+        .await
+    }
+
+    pub async fn get_agreement(
+        &self,
+        id: &str,
+    ) -> Result<EndUserAgreement, NordigenError> {
+        self.json::<EndUserAgreement>(
+            self.get(&format!("agreements/enduser/{}/", id)),
+        )
+        .await
+    }
+
+    /// List one page of end user agreements.
+    pub async fn list_agreements(
+        &self,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Result<Page<EndUserAgreement>, NordigenError> {
+        let mut req = self.get("agreements/enduser/");
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+        if let Some(offset) = offset {
+            req = req.query(&[("offset", offset)]);
+        }
+        self.json::<Page<EndUserAgreement>>(req).await
+    }
+
+    /// Stream all end user agreements, fetching further pages as needed.
+    pub fn all_agreements(
+        &self,
+    ) -> impl Stream<Item = Result<EndUserAgreement, NordigenError>> {
+        self.paginate::<EndUserAgreement>("agreements/enduser/")
+    }
+
+    /// Delete an agreement. Only agreements that have not been accepted may
+    /// be deleted.
+    pub async fn delete_agreement(
+        &self,
+        id: &str,
+    ) -> Result<(), NordigenError> {
+        self.send(self.delete(&format!("agreements/enduser/{}/", id)))
+            .await?;
+        Ok(())
+    }
+
+    /// Accept an agreement on behalf of the end user, for flows where the
+    /// user consents through our own interface rather than the bank's.
+    pub async fn accept_agreement(
+        &self,
+        id: &str,
+        user_agent: &str,
+        ip_address: &str,
+    ) -> Result<EndUserAgreement, NordigenError> {
+        self.json::<EndUserAgreement>(
+            self.put(&format!("agreements/enduser/{}/accept/", id))
+                .json(&AcceptAgreementRequest {
+                    user_agent,
+                    ip_address,
+                }),
+        )
+        .await
+    }
+}
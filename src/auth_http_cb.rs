@@ -13,92 +13,686 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-
-fn send_response(stream: &mut TcpStream) {
-    let response_vec = vec![
-        "HTTP/1.1 200 OK",
-        "Content-Type: text/html; charset=UTF-8",
-        "",
-        "<html>",
-        "<body>",
-        "<h2>Thank You!</h2>",
-        "<p>You can now go back to the tool :)</p>",
-        "</body>",
-        "</html>",
-        "",
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::NordigenConfig;
+use crate::error::NordigenError;
+
+/// Where to listen for the bank's redirect once the user has gone through
+/// the authorization flow.
+#[derive(Clone, Debug)]
+pub struct CallbackConfig {
+    pub bind_addr: IpAddr,
+    /// Port to listen on; 0 picks a free ephemeral port.
+    pub port: u16,
+    /// Path the bank redirects to.
+    pub path: String,
+    /// URL the bank should redirect to, when it is not the listener's own
+    /// address, e.g. behind a reverse proxy.
+    pub redirect_url: Option<String>,
+    /// How long to wait for the user to complete the flow; forever if not
+    /// set.
+    pub timeout: Option<Duration>,
+    /// What to show the user once the bank redirects back.
+    pub pages: LandingPages,
+}
+
+/// Pages shown to the user at the end of the flow.
+///
+/// Templates may contain the `{bank_name}`, `{account_count}` and `{error}`
+/// placeholders, which are replaced with HTML-escaped values.
+#[derive(Clone, Debug)]
+pub struct LandingPages {
+    pub success: String,
+    pub failure: String,
+    /// If set, the user is redirected here instead of being shown a page,
+    /// with a `status` query parameter of `approved`, `cancelled` or
+    /// `error`.
+    pub final_url: Option<String>,
+}
+
+pub const DEFAULT_SUCCESS_PAGE: &str = "<html>
+<body>
+<h2>Thank You!</h2>
+<p>{bank_name} is now linked, with {account_count} account(s).</p>
+<p>You can now go back to the tool :)</p>
+</body>
+</html>
+";
+
+pub const DEFAULT_FAILURE_PAGE: &str = "<html>
+<body>
+<h2>Something went wrong</h2>
+<p>{bank_name} could not be linked: {error}</p>
+<p>You can go back to the tool and try again.</p>
+</body>
+</html>
+";
+
+impl Default for LandingPages {
+    fn default() -> Self {
+        LandingPages {
+            success: String::from(DEFAULT_SUCCESS_PAGE),
+            failure: String::from(DEFAULT_FAILURE_PAGE),
+            final_url: None,
+        }
+    }
+}
+
+impl LandingPages {
+    fn render(
+        &self,
+        outcome: &CallbackOutcome,
+        bank_name: &str,
+        account_count: usize,
+    ) -> String {
+        let (template, error) = match outcome {
+            CallbackOutcome::Approved => (&self.success, String::new()),
+            CallbackOutcome::UserCancelled { details } => (
+                &self.failure,
+                details
+                    .clone()
+                    .unwrap_or_else(|| String::from("cancelled by user")),
+            ),
+            CallbackOutcome::InstitutionError { error, details } => {
+                (&self.failure, details.clone().unwrap_or(error.clone()))
+            }
+        };
+        template
+            .replace("{bank_name}", &html_escape(bank_name))
+            .replace("{account_count}", &account_count.to_string())
+            .replace("{error}", &html_escape(&error))
+    }
+}
+
+impl Default for CallbackConfig {
+    fn default() -> Self {
+        CallbackConfig {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 1337,
+            path: String::from("/"),
+            redirect_url: None,
+            timeout: None,
+            pages: LandingPages::default(),
+        }
+    }
+}
+
+impl CallbackConfig {
+    fn normalized_path(&self) -> String {
+        if self.path.starts_with('/') {
+            self.path.clone()
+        } else {
+            format!("/{}", self.path)
+        }
+    }
+
+    /// URL the bank should redirect to: the configured one, or the
+    /// listener's address otherwise. The latter can't be known ahead of
+    /// binding for an ephemeral port, which is thus an error.
+    pub fn redirect_url(&self) -> Result<String, NordigenError> {
+        match &self.redirect_url {
+            Some(url) => Ok(url.clone()),
+            None if self.port == 0 => {
+                Err(NordigenError::InvalidRequest(String::from(
+                    "a redirect URL or a fixed port is needed without a \
+                     listener",
+                )))
+            }
+            None => Ok(format!(
+                "http://{}{}",
+                SocketAddr::new(self.bind_addr, self.port),
+                self.normalized_path()
+            )),
+        }
+    }
+
+    pub fn from_config(config: &NordigenConfig) -> CallbackConfig {
+        CallbackConfig {
+            bind_addr: config.callback_addr.ip(),
+            port: config.callback_addr.port(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Listener for the bank's redirect, bound ahead of starting the flow so
+/// the redirect URL is known, even for ephemeral ports.
+pub struct CallbackListener {
+    listener: TcpListener,
+    path: String,
+    redirect_url: String,
+}
+
+impl CallbackListener {
+    pub async fn bind(
+        config: &CallbackConfig,
+    ) -> Result<CallbackListener, NordigenError> {
+        let addr = SocketAddr::new(config.bind_addr, config.port);
+        let listener = match TcpListener::bind(addr).await {
+            Err(err) => {
+                return Err(NordigenError::Callback(format!(
+                    "Unable to bind to {}: {}",
+                    addr, err
+                )));
+            }
+            Ok(res) => res,
+        };
+
+        let path = config.normalized_path();
+        let redirect_url = match &config.redirect_url {
+            Some(url) => url.clone(),
+            None => format!("http://{}{}", listener.local_addr()?, path),
+        };
+
+        Ok(CallbackListener {
+            listener,
+            path,
+            redirect_url,
+        })
+    }
+
+    pub fn redirect_url(&self) -> &str {
+        &self.redirect_url
+    }
+}
+
+/// Longest request head we are willing to read from a client.
+const MAX_REQUEST_LEN: u64 = 8192;
+
+/// How long a single client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most connections we read requests from at once; further ones are dropped
+/// until some finish.
+const MAX_PENDING_CONNECTIONS: usize = 64;
+
+/// How long to back off after failing to accept a connection, e.g. when out
+/// of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+async fn send_response(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[String],
+    body: &str,
+) {
+    let mut response_vec = vec![
+        format!("HTTP/1.1 {}", status),
+        String::from("Content-Type: text/html; charset=UTF-8"),
+        format!("Content-Length: {}", body.len()),
+        String::from("Connection: close"),
     ];
+    response_vec.extend_from_slice(headers);
+    response_vec.push(String::new());
+    response_vec.push(body.to_string());
     let response = response_vec.join("\r\n");
-    match stream.write(response.as_bytes()) {
-        Ok(_) => {}
-        Err(error) => {
-            println!("Error sending response: {}", error);
+    // the browser may well have gone away already; there is nobody to tell.
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// How the user's trip through the bank ended, as reported by the bank's
+/// redirect.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CallbackOutcome {
+    Approved,
+    UserCancelled {
+        details: Option<String>,
+    },
+    InstitutionError {
+        error: String,
+        details: Option<String>,
+    },
+}
+
+impl CallbackOutcome {
+    fn from_request(req: &CallbackRequest) -> CallbackOutcome {
+        let error = match req.get("error") {
+            None => return CallbackOutcome::Approved,
+            Some(v) => v.to_string(),
+        };
+        let details = req.get("details").map(|v| v.to_string());
+
+        if error.to_lowercase().contains("cancel") {
+            CallbackOutcome::UserCancelled { details }
+        } else {
+            CallbackOutcome::InstitutionError { error, details }
+        }
+    }
+
+    /// Short name of the outcome, as passed on to the final URL.
+    pub fn status(&self) -> &'static str {
+        match self {
+            CallbackOutcome::Approved => "approved",
+            CallbackOutcome::UserCancelled { .. } => "cancelled",
+            CallbackOutcome::InstitutionError { .. } => "error",
         }
     }
 }
 
-fn parse_request(req: &Vec<String>) -> Result<HashMap<&str, &str>, String> {
-    if req.len() == 0 {
-        return Err(String::from("empty request"));
+/// Add a `status` query parameter to `url`, ahead of any fragment.
+fn with_status(url: &str, status: &str) -> String {
+    let (base, fragment) = match url.split_once('#') {
+        None => (url, None),
+        Some((base, fragment)) => (base, Some(fragment)),
+    };
+    let sep = if base.contains('?') { '&' } else { '?' };
+    let mut res = format!("{}{}status={}", base, sep, status);
+    if let Some(fragment) = fragment {
+        res.push('#');
+        res.push_str(fragment);
     }
-    let request_line: Vec<_> = req[0].split_whitespace().collect();
-    if request_line.len() < 3 {
-        return Err(format!("Unexpected request line: {}", req[0]));
+    res
+}
+
+impl std::fmt::Display for CallbackOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CallbackOutcome::Approved => write!(f, "approved"),
+            CallbackOutcome::UserCancelled { details: None } => {
+                write!(f, "cancelled by user")
+            }
+            CallbackOutcome::UserCancelled {
+                details: Some(details),
+            } => {
+                write!(f, "cancelled by user: {}", details)
+            }
+            CallbackOutcome::InstitutionError {
+                error,
+                details: None,
+            } => write!(f, "institution error: {}", error),
+            CallbackOutcome::InstitutionError {
+                error,
+                details: Some(details),
+            } => write!(f, "institution error: {}: {}", error, details),
+        }
     }
-    let (method, target) = (request_line[0], request_line[1]);
-    if method.to_lowercase() != "get" {
-        return Err(format!("Unexpected method: {}", method));
+}
+
+fn html_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// The bank's redirect, held open until we know what to tell the user.
+pub struct PendingCallback {
+    stream: TcpStream,
+    pub outcome: CallbackOutcome,
+}
+
+impl PendingCallback {
+    /// Answer the user's browser with the page matching the outcome, or
+    /// redirect it to the final URL if there is one.
+    pub async fn respond(
+        mut self,
+        pages: &LandingPages,
+        bank_name: &str,
+        account_count: usize,
+    ) {
+        if let Some(url) = &pages.final_url {
+            let location = format!(
+                "Location: {}",
+                with_status(url, self.outcome.status())
+            );
+            send_response(&mut self.stream, "303 See Other", &[location], "")
+                .await;
+            return;
+        }
+        let page = pages.render(&self.outcome, bank_name, account_count);
+        send_response(&mut self.stream, "200 OK", &[], &page).await;
     }
+}
 
-    let p = target.find("?");
-    if p.is_none() {
-        return Err(String::from("No parameters provided!"));
+/// Read a request's head, up to the first empty line.
+async fn read_request(stream: &mut TcpStream) -> Vec<String> {
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_LEN);
+    let mut request: Vec<String> = Vec::new();
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line).await {
+            Err(_) | Ok(0) => break,
+            Ok(_) => {}
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            break;
+        }
+        request.push(line);
     }
-    let pos = p.unwrap();
-    if target.len() < pos + 1 {
-        return Err(String::from("Parameters not provided."));
+    request
+}
+
+/// Read a request's head from a freshly accepted connection, giving up on
+/// clients that are too slow about it.
+async fn read_connection(
+    mut stream: TcpStream,
+) -> Option<(TcpStream, Vec<String>)> {
+    match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await
+    {
+        Err(_) => None,
+        Ok(request) => Some((stream, request)),
     }
-    let params_str = &target[pos + 1..];
-    // println!("Parameters: {}", params_str);
-    let mut map: HashMap<&str, &str> = HashMap::new();
-    params_str.split("&").for_each(|v| {
-        let kv: Vec<&str> = v.split("=").collect();
-        if kv.len() != 2 {
-            return;
+}
+
+/// Longest request line we accept.
+const MAX_LINE_LEN: usize = 4096;
+
+/// The parts of an HTTP request we care about: its method, and its decoded
+/// path and query parameters.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CallbackRequest {
+    pub method: String,
+    pub path: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl CallbackRequest {
+    /// First value of the query parameter `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Percent-decode `input`, optionally taking `+` to mean a space as in
+/// `application/x-www-form-urlencoded`. Malformed escapes are kept as is.
+fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_as_space => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(hi), Some(lo)) => {
+                        out.push(hi << 4 | lo);
+                        i += 2;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            c => out.push(c),
         }
-        let (key, value) = (kv[0], kv[1]);
-        map.insert(key, value);
-    });
-    Ok(map)
-}
-
-pub fn wait_for_response() -> Result<String, String> {
-    let listener = TcpListener::bind("127.0.0.1:1337").unwrap();
-    let mut stream = listener
-        .incoming()
-        .filter_map(Result::ok)
-        .take(1)
-        .next()
-        .unwrap();
-    let reader = BufReader::new(&mut stream);
-    let request: Vec<_> = reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
-
-    let res: Result<String, String> = match parse_request(&request) {
-        Err(error) => Err(format!("Error obtaining ref: {}", error)),
-        Ok(map) => {
-            if let Some(val) = map.get("ref") {
-                Ok(String::from(*val))
-            } else {
-                Err(String::from("Callback did not provide ref"))
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            None => (percent_decode(pair, true), String::new()),
+            Some((key, value)) => {
+                (percent_decode(key, true), percent_decode(value, true))
+            }
+        })
+        .collect()
+}
+
+/// Parse a request head, as read up to its first empty line.
+pub fn parse_request(req: &[String]) -> Result<CallbackRequest, String> {
+    if req.is_empty() {
+        return Err(String::from("empty request"));
+    }
+    let line = &req[0];
+    if line.len() > MAX_LINE_LEN {
+        return Err(format!("Request line longer than {} bytes", MAX_LINE_LEN));
+    }
+
+    let request_line: Vec<_> = line.split(' ').collect();
+    if request_line.len() != 3 {
+        return Err(format!("Unexpected request line: {}", line));
+    }
+    let (method, target, version) =
+        (request_line[0], request_line[1], request_line[2]);
+    if !version.starts_with("HTTP/") {
+        return Err(format!("Unexpected HTTP version: {}", version));
+    }
+    if !method.eq_ignore_ascii_case("get") {
+        return Err(format!("Unexpected method: {}", method));
+    }
+    if !target.starts_with('/') {
+        return Err(format!("Unexpected request target: {}", target));
+    }
+
+    // browsers don't send fragments, but don't trip on them either.
+    let target = match target.split_once('#') {
+        None => target,
+        Some((t, _)) => t,
+    };
+    let (path, params) = match target.split_once('?') {
+        None => (target, Vec::new()),
+        Some((path, query)) => (path, parse_query(query)),
+    };
+
+    Ok(CallbackRequest {
+        method: method.to_uppercase(),
+        path: percent_decode(path, false),
+        params,
+    })
+}
+
+/// Wait for the bank to redirect back with `expected_ref`, answering and
+/// ignoring any other requests on the way (favicons, probes, stale tabs).
+/// The bank's request is left pending, for the caller to respond to.
+pub async fn wait_for_response(
+    listener: &CallbackListener,
+    expected_ref: &str,
+    timeout: Option<Duration>,
+    cancel: &CancellationToken,
+) -> Result<PendingCallback, NordigenError> {
+    // connections are read concurrently, so that one which never sends a
+    // request (a browser preconnect, a port probe) can't hold up the others.
+    let accept_loop = async {
+        let mut pending = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                res = listener.listener.accept() => match res {
+                    // usually transient, so keep waiting for the bank.
+                    Err(_) => tokio::time::sleep(ACCEPT_BACKOFF).await,
+                    Ok((stream, _)) => {
+                        if pending.len() < MAX_PENDING_CONNECTIONS {
+                            pending.push(read_connection(stream));
+                        }
+                    }
+                },
+                Some(res) = pending.next(), if !pending.is_empty() => {
+                    let (mut stream, request) = match res {
+                        None => continue,
+                        Some(res) => res,
+                    };
+                    let outcome = match parse_request(&request) {
+                        Ok(req)
+                            if req.path == listener.path
+                                && req.get("ref") == Some(expected_ref) =>
+                        {
+                            Some(CallbackOutcome::from_request(&req))
+                        }
+                        _ => None,
+                    };
+                    match outcome {
+                        Some(outcome) => {
+                            return PendingCallback { stream, outcome };
+                        }
+                        None => {
+                            send_response(
+                                &mut stream,
+                                "404 Not Found",
+                                &[],
+                                "",
+                            )
+                            .await;
+                        }
+                    }
+                }
             }
         }
     };
-    send_response(&mut stream);
-    return res;
+
+    let timeout = async {
+        match timeout {
+            None => std::future::pending::<()>().await,
+            Some(duration) => tokio::time::sleep(duration).await,
+        }
+    };
+
+    tokio::select! {
+        res = accept_loop => Ok(res),
+        _ = timeout => Err(NordigenError::CallbackTimeout),
+        _ = cancel.cancelled() => Err(NordigenError::CallbackCancelled),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<CallbackRequest, String> {
+        parse_request(&[line.to_string()])
+    }
+
+    #[test]
+    fn test_parse_simple() {
+        let req = parse("GET /?ref=abc&foo=bar HTTP/1.1").unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/");
+        assert_eq!(req.get("ref"), Some("abc"));
+        assert_eq!(req.get("foo"), Some("bar"));
+        assert_eq!(req.get("baz"), None);
+    }
+
+    #[test]
+    fn test_parse_decoding() {
+        let req = parse(
+            "GET /cb%20path?ref=a%2Fb%3Dc&details=User+cancelled%21 HTTP/1.1",
+        )
+        .unwrap();
+        assert_eq!(req.path, "/cb path");
+        assert_eq!(req.get("ref"), Some("a/b=c"));
+        assert_eq!(req.get("details"), Some("User cancelled!"));
+
+        // '=' within a value is kept, '+' in the path is not a space.
+        let req = parse("GET /a+b?ref=x=y HTTP/1.1").unwrap();
+        assert_eq!(req.path, "/a+b");
+        assert_eq!(req.get("ref"), Some("x=y"));
+
+        // malformed escapes are kept verbatim.
+        let req = parse("GET /?ref=100%&x=%zz&y=%4 HTTP/1.1").unwrap();
+        assert_eq!(req.get("ref"), Some("100%"));
+        assert_eq!(req.get("x"), Some("%zz"));
+        assert_eq!(req.get("y"), Some("%4"));
+
+        // multi-byte utf-8.
+        let req = parse("GET /?name=Jo%C3%A3o HTTP/1.1").unwrap();
+        assert_eq!(req.get("name"), Some("João"));
+    }
+
+    #[test]
+    fn test_parse_repeated_and_empty() {
+        let req = parse("GET /?ref=first&&ref=second&flag HTTP/1.1").unwrap();
+        assert_eq!(req.get("ref"), Some("first"));
+        assert_eq!(
+            req.params,
+            vec![
+                (String::from("ref"), String::from("first")),
+                (String::from("ref"), String::from("second")),
+                (String::from("flag"), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_fragment() {
+        let req = parse("GET /?ref=abc#frag=1 HTTP/1.1").unwrap();
+        assert_eq!(req.get("ref"), Some("abc"));
+        assert_eq!(req.get("frag"), None);
+
+        let req = parse("GET /favicon.ico HTTP/1.1").unwrap();
+        assert_eq!(req.path, "/favicon.ico");
+        assert!(req.params.is_empty());
+    }
+
+    #[test]
+    fn test_parse_rejects() {
+        assert!(parse_request(&[]).is_err());
+        assert!(parse("").is_err());
+        assert!(parse("GET /?ref=abc").is_err());
+        assert!(parse("GET /?ref=abc HTTP/1.1 extra").is_err());
+        assert!(parse("POST /?ref=abc HTTP/1.1").is_err());
+        assert!(parse("GET ?ref=abc HTTP/1.1").is_err());
+        assert!(parse("GET /?ref=abc FTP/1.0").is_err());
+
+        let long = format!("GET /?ref={} HTTP/1.1", "a".repeat(MAX_LINE_LEN));
+        assert!(parse(&long).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_does_not_block() {
+        let config = CallbackConfig {
+            port: 0,
+            ..CallbackConfig::default()
+        };
+        let listener = CallbackListener::bind(&config).await.unwrap();
+        let addr = listener.listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+
+        // connects first, but never sends a request.
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        let mut bank = TcpStream::connect(addr).await.unwrap();
+        bank.write_all(b"GET /?ref=abc HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(2),
+            wait_for_response(&listener, "abc", None, &cancel),
+        )
+        .await
+        .expect("callback held up by an idle connection")
+        .unwrap();
+        assert_eq!(res.outcome, CallbackOutcome::Approved);
+    }
+
+    #[test]
+    fn test_final_url_status() {
+        assert_eq!(
+            with_status("https://example.com/done", "approved"),
+            "https://example.com/done?status=approved"
+        );
+        assert_eq!(
+            with_status("https://example.com/done?x=1#top", "error"),
+            "https://example.com/done?x=1&status=error#top"
+        );
+    }
 }
@@ -17,79 +17,58 @@ use std::collections::HashMap;
 
 use serde::Deserialize;
 
+use crate::client::NordigenClient;
 use crate::config::NordigenConfig;
+use crate::error::NordigenError;
+use crate::secret::Secret;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 pub struct AuthorizeReply {
-    pub access: String,
+    pub access: Secret,
     pub access_expires: u32,
-    pub refresh: String,
+    pub refresh: Secret,
     pub refresh_expires: u32,
 }
 
 #[derive(Deserialize)]
 struct RefreshReply {
-    pub access: String,
+    pub access: Secret,
     pub access_expires: u32,
 }
 
-pub async fn authorize(
-    config: &NordigenConfig,
-) -> Result<AuthorizeReply, String> {
-    let mut map: HashMap<&str, &String> = HashMap::new();
-    map.insert("secret_id", &config.secret_id);
-    map.insert("secret_key", &config.secret_key);
-
-    let client = reqwest::Client::new();
-    let res = match client
-        .post("https://ob.nordigen.com/api/v2/token/new/")
-        .header("accept", "application/json")
-        .header("Content-Type", "application/json")
-        .json(&map)
-        .send()
-        .await
-    {
-        Err(error) => {
-            return Err(format!("Unable to obtain token: {}", error));
-        }
-        Ok(res) => res,
-    };
+impl NordigenClient {
+    /// Obtain a new access/refresh token pair, and use the new access token
+    /// for subsequent requests.
+    pub async fn authorize(
+        &self,
+        config: &NordigenConfig,
+    ) -> Result<AuthorizeReply, NordigenError> {
+        let mut map: HashMap<&str, &str> = HashMap::new();
+        map.insert("secret_id", &config.secret_id);
+        map.insert("secret_key", config.secret_key.expose());
 
-    let value: AuthorizeReply = match res.json::<AuthorizeReply>().await {
-        Err(error) => {
-            return Err(format!("Unable to obtain response value: {error}"));
-        }
-        Ok(res) => res,
-    };
-
-    Ok(value)
-}
+        let value: AuthorizeReply = self
+            .json(self.post_anonymous("token/new/").json(&map))
+            .await?;
 
-pub async fn refresh(refresh_token: &String) -> Result<(String, u32), String> {
-    let mut map: HashMap<&str, &String> = HashMap::new();
-    map.insert("refresh", refresh_token);
+        self.set_token(&value.access);
+        Ok(value)
+    }
 
-    let client = reqwest::Client::new();
-    let res = match client
-        .post("https://ob.nordigen.com/api/v2/token/refresh/")
-        .header("accept", "application/json")
-        .header("Content-Type", "application/json")
-        .json(&map)
-        .send()
-        .await
-    {
-        Err(error) => {
-            return Err(format!("Unable to refresh token: {}", error));
-        }
-        Ok(res) => res,
-    };
+    /// Obtain a new access token from a refresh token, and use it for
+    /// subsequent requests.
+    pub async fn refresh(
+        &self,
+        refresh_token: &str,
+    ) -> Result<(Secret, u32), NordigenError> {
+        let mut map: HashMap<&str, &str> = HashMap::new();
+        map.insert("refresh", refresh_token);
 
-    let value: RefreshReply = match res.json::<RefreshReply>().await {
-        Err(error) => {
-            return Err(format!("Unable to obtain response value: {}", error));
-        }
-        Ok(res) => res,
-    };
+        let value: RefreshReply = self
+            .json(self.post_anonymous("token/refresh/").json(&map))
+            .await?;
 
-    Ok((value.access, value.access_expires))
+        self.set_token(&value.access);
+        Ok((value.access, value.access_expires))
+    }
 }
@@ -0,0 +1,597 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::banks::BankEntry;
+use crate::client::NordigenClient;
+use crate::error::NordigenError;
+use crate::store::FileStore;
+
+/// Default time for which a cached catalogue is used without refreshing.
+pub const DEFAULT_TTL_SECS: i64 = 24 * 60 * 60;
+
+/// The institutions available in a country, as fetched at some point.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Catalogue {
+    pub fetched_at: DateTime<Utc>,
+    pub banks: Vec<BankEntry>,
+    /// Validators from the response, if any, for a conditional refresh.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+/// Where a catalogue returned by `BankCache::banks()` came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CatalogueSource {
+    /// The cache, which had not yet expired.
+    Cache,
+    /// The API; the cache has been updated.
+    Fetched,
+    /// An expired cache, which the API confirmed is still current; its
+    /// expiry has been pushed back.
+    NotModified,
+    /// An expired cache, because the API could not be reached.
+    Stale,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rename {
+    pub id: String,
+    pub old_name: String,
+    pub new_name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct HistoryChange {
+    pub id: String,
+    pub name: String,
+    pub old_days: Option<u32>,
+    pub new_days: Option<u32>,
+}
+
+/// How a catalogue changed between two fetches.
+#[derive(Clone, Debug, Default)]
+pub struct CatalogueDiff {
+    pub added: Vec<BankEntry>,
+    pub removed: Vec<BankEntry>,
+    pub renamed: Vec<Rename>,
+    pub history_changed: Vec<HistoryChange>,
+}
+
+impl CatalogueDiff {
+    pub fn between(old: &[BankEntry], new: &[BankEntry]) -> CatalogueDiff {
+        let old: BTreeMap<&str, &BankEntry> =
+            old.iter().map(|b| (b.id.as_str(), b)).collect();
+        let new: BTreeMap<&str, &BankEntry> =
+            new.iter().map(|b| (b.id.as_str(), b)).collect();
+
+        let mut diff = CatalogueDiff::default();
+        for (id, bank) in &new {
+            let prev = match old.get(id) {
+                None => {
+                    diff.added.push((*bank).clone());
+                    continue;
+                }
+                Some(prev) => prev,
+            };
+            if prev.name != bank.name {
+                diff.renamed.push(Rename {
+                    id: id.to_string(),
+                    old_name: prev.name.clone(),
+                    new_name: bank.name.clone(),
+                });
+            }
+            if prev.history_days() != bank.history_days() {
+                diff.history_changed.push(HistoryChange {
+                    id: id.to_string(),
+                    name: bank.name.clone(),
+                    old_days: prev.history_days(),
+                    new_days: bank.history_days(),
+                });
+            }
+        }
+        for (id, bank) in &old {
+            if !new.contains_key(id) {
+                diff.removed.push((*bank).clone());
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.renamed.is_empty()
+            && self.history_changed.is_empty()
+    }
+}
+
+impl std::fmt::Display for CatalogueDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let days = |d: Option<u32>| match d {
+            None => String::from("unknown"),
+            Some(d) => d.to_string(),
+        };
+        for bank in &self.added {
+            writeln!(f, "+ {} ({})", bank.name, bank.id)?;
+        }
+        for bank in &self.removed {
+            writeln!(f, "- {} ({})", bank.name, bank.id)?;
+        }
+        for rename in &self.renamed {
+            writeln!(
+                f,
+                "~ {} ({}): renamed to {}",
+                rename.old_name, rename.id, rename.new_name
+            )?;
+        }
+        for change in &self.history_changed {
+            writeln!(
+                f,
+                "~ {} ({}): history days {} -> {}",
+                change.name,
+                change.id,
+                days(change.old_days),
+                days(change.new_days)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A catalogue obtained through `BankCache::banks()`.
+pub struct CachedBanks {
+    pub catalogue: Catalogue,
+    pub source: CatalogueSource,
+    /// Changes from the previously cached catalogue, when one was fetched
+    /// and there was something cached to compare it with.
+    pub diff: Option<CatalogueDiff>,
+    /// Why the API could not be reached, for a stale catalogue, or why a
+    /// fetched one could not be cached.
+    pub error: Option<NordigenError>,
+}
+
+/// Keeps the institution catalogue on disk, one file per country, so it
+/// needs not be fetched on every run.
+pub struct BankCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl BankCache {
+    pub fn new(dir: &Path) -> BankCache {
+        BankCache {
+            dir: dir.to_path_buf(),
+            ttl: Duration::seconds(DEFAULT_TTL_SECS),
+        }
+    }
+
+    /// `nordigen/institutions` under the user's cache directory.
+    pub fn default_dir() -> Option<PathBuf> {
+        Some(dirs::cache_dir()?.join("nordigen").join("institutions"))
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    fn store(&self, country: Option<&str>) -> Result<FileStore, NordigenError> {
+        let name = match country {
+            None => String::from("all"),
+            Some(cc) => {
+                if cc.is_empty() || !cc.chars().all(|c| c.is_ascii_alphabetic())
+                {
+                    return Err(NordigenError::InvalidRequest(format!(
+                        "invalid country code '{}'",
+                        cc
+                    )));
+                }
+                cc.to_uppercase()
+            }
+        };
+        Ok(FileStore::new(&self.dir.join(format!("{}.json", name))))
+    }
+
+    /// The cached catalogue for `country`, or for all countries if `None`,
+    /// regardless of its age. A corrupted cache is treated as empty.
+    pub fn load(
+        &self,
+        country: Option<&str>,
+    ) -> Result<Option<Catalogue>, NordigenError> {
+        match self.store(country)?.read_raw()? {
+            None => Ok(None),
+            Some(contents) => Ok(serde_json::from_slice(&contents).ok()),
+        }
+    }
+
+    pub fn save(
+        &self,
+        country: Option<&str>,
+        catalogue: &Catalogue,
+    ) -> Result<(), NordigenError> {
+        let contents = match serde_json::to_vec_pretty(catalogue) {
+            Err(err) => {
+                return Err(NordigenError::Store(format!(
+                    "unable to serialize catalogue: {}",
+                    err
+                )));
+            }
+            Ok(res) => res,
+        };
+        self.store(country)?.write_raw(&contents)
+    }
+
+    pub fn is_fresh(&self, catalogue: &Catalogue) -> bool {
+        Utc::now() - catalogue.fetched_at < self.ttl
+    }
+
+    /// The catalogue for `country`, from the cache if it has not expired,
+    /// or else from the API. Should the API be unreachable, an expired
+    /// cache is returned instead.
+    pub async fn banks(
+        &self,
+        client: &NordigenClient,
+        country: Option<&str>,
+    ) -> Result<CachedBanks, NordigenError> {
+        if let Some(catalogue) = self.cached(country)? {
+            if self.is_fresh(&catalogue) {
+                return Ok(CachedBanks {
+                    catalogue,
+                    source: CatalogueSource::Cache,
+                    diff: None,
+                    error: None,
+                });
+            }
+            return self.fetch(client, country, Some(catalogue)).await;
+        }
+        self.fetch(client, country, None).await
+    }
+
+    /// Fetch the catalogue for `country` from the API, whether or not the
+    /// cache has expired.
+    pub async fn refresh(
+        &self,
+        client: &NordigenClient,
+        country: Option<&str>,
+    ) -> Result<CachedBanks, NordigenError> {
+        let cached = self.cached(country)?;
+        self.fetch(client, country, cached).await
+    }
+
+    /// Like `load()`, but a cache that can't be read at all is taken to be
+    /// missing: it must never keep us from asking the API instead.
+    fn cached(
+        &self,
+        country: Option<&str>,
+    ) -> Result<Option<Catalogue>, NordigenError> {
+        match self.load(country) {
+            Err(NordigenError::InvalidRequest(msg)) => {
+                Err(NordigenError::InvalidRequest(msg))
+            }
+            Err(_) => Ok(None),
+            Ok(res) => Ok(res),
+        }
+    }
+
+    async fn fetch(
+        &self,
+        client: &NordigenClient,
+        country: Option<&str>,
+        cached: Option<Catalogue>,
+    ) -> Result<CachedBanks, NordigenError> {
+        let fetched =
+            match list_banks_if_changed(client, country, &cached).await {
+                Err(err) if err.is_transient() && cached.is_some() => {
+                    return Ok(CachedBanks {
+                        catalogue: cached.unwrap(),
+                        source: CatalogueSource::Stale,
+                        diff: None,
+                        error: Some(err),
+                    });
+                }
+                Err(err) => return Err(err),
+                Ok(res) => res,
+            };
+
+        let (catalogue, source, diff) = match (fetched, cached) {
+            (Some(catalogue), cached) => {
+                let diff = cached.map(|old| {
+                    CatalogueDiff::between(&old.banks, &catalogue.banks)
+                });
+                (catalogue, CatalogueSource::Fetched, diff)
+            }
+            (None, Some(mut catalogue)) => {
+                catalogue.fetched_at = Utc::now();
+                let diff = Some(CatalogueDiff::default());
+                (catalogue, CatalogueSource::NotModified, diff)
+            }
+            // not modified, but we sent no validators.
+            (None, None) => {
+                return Err(NordigenError::Http {
+                    status: StatusCode::NOT_MODIFIED.as_u16(),
+                    error: None,
+                    body: String::new(),
+                });
+            }
+        };
+
+        // the catalogue is good to use even if we fail to keep it.
+        let error = self.save(country, &catalogue).err();
+        Ok(CachedBanks {
+            catalogue,
+            source,
+            diff,
+            error,
+        })
+    }
+}
+
+/// Fetch the institutions list, unless it has not changed since `cached`
+/// was fetched, in which case `None` is returned.
+async fn list_banks_if_changed(
+    client: &NordigenClient,
+    country: Option<&str>,
+    cached: &Option<Catalogue>,
+) -> Result<Option<Catalogue>, NordigenError> {
+    let mut req = client.list_banks_request(country);
+    if let Some(cached) = cached {
+        if let Some(etag) = &cached.etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let (banks, headers) =
+        match client.json_if_modified::<Vec<BankEntry>>(req).await? {
+            None => return Ok(None),
+            Some(res) => res,
+        };
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    };
+    Ok(Some(Catalogue {
+        fetched_at: Utc::now(),
+        banks,
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    fn bank(id: &str, name: &str, days: &str) -> BankEntry {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": name,
+            "bic": "",
+            "transaction_total_days": days,
+            "countries": ["PT"],
+            "logo": "",
+        }))
+        .unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nordigen-test-{}-bank-cache-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn catalogue(age: Duration) -> Catalogue {
+        Catalogue {
+            fetched_at: Utc::now() - age,
+            banks: vec![bank("A", "Bank A", "90")],
+            etag: Some(String::from("\"v1\"")),
+            last_modified: None,
+        }
+    }
+
+    /// Answer one request with `response`, handing back the request head.
+    async fn serve(response: &str) -> (NordigenClient, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let response = response.to_string();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                head.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&head).to_lowercase()
+        });
+        let client =
+            NordigenClient::with_base_url(&format!("http://{}", addr)).unwrap();
+        (client, handle)
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = vec![
+            bank("A", "Bank A", "90"),
+            bank("B", "Bank B", "90"),
+            bank("C", "Bank C", "90"),
+            bank("D", "Bank D", "90"),
+        ];
+        let new = vec![
+            bank("A", "Bank A", "90"),
+            bank("C", "Bank C2", "90"),
+            bank("D", "Bank D", "730"),
+            bank("E", "Bank E", "90"),
+        ];
+        let diff = CatalogueDiff::between(&old, &new);
+        assert!(!diff.is_empty());
+
+        let ids = |banks: &[BankEntry]| -> Vec<String> {
+            banks.iter().map(|b| b.id.clone()).collect()
+        };
+        assert_eq!(ids(&diff.added), ["E"]);
+        assert_eq!(ids(&diff.removed), ["B"]);
+        assert_eq!(diff.renamed.len(), 1);
+        assert_eq!(diff.renamed[0].id, "C");
+        assert_eq!(diff.renamed[0].old_name, "Bank C");
+        assert_eq!(diff.renamed[0].new_name, "Bank C2");
+        assert_eq!(diff.history_changed.len(), 1);
+        assert_eq!(diff.history_changed[0].id, "D");
+        assert_eq!(diff.history_changed[0].old_days, Some(90));
+        assert_eq!(diff.history_changed[0].new_days, Some(730));
+
+        assert!(CatalogueDiff::between(&old, &old).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fresh() {
+        let dir = temp_dir("fresh");
+        let cache = BankCache::new(&dir);
+        cache
+            .save(Some("PT"), &catalogue(Duration::hours(1)))
+            .unwrap();
+
+        // nothing listens there; a fresh cache must not need it.
+        let client =
+            NordigenClient::with_base_url("http://127.0.0.1:9").unwrap();
+        let res = cache.banks(&client, Some("pt")).await.unwrap();
+        assert_eq!(res.source, CatalogueSource::Cache);
+        assert_eq!(res.catalogue.banks.len(), 1);
+        assert!(res.diff.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_not_modified() {
+        let dir = temp_dir("not-modified");
+        let cache = BankCache::new(&dir);
+        cache
+            .save(Some("PT"), &catalogue(Duration::days(2)))
+            .unwrap();
+
+        let (client, server) =
+            serve("HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n")
+                .await;
+        let res = cache.banks(&client, Some("PT")).await.unwrap();
+        let head = server.await.unwrap();
+        assert!(head.starts_with("get /institutions/?country=pt "));
+        assert!(head.contains("if-none-match: \"v1\"\r\n"));
+
+        assert_eq!(res.source, CatalogueSource::NotModified);
+        assert!(res.diff.unwrap().is_empty());
+        assert!(res.error.is_none());
+        let saved = cache.load(Some("PT")).unwrap().unwrap();
+        assert!(cache.is_fresh(&saved));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale() {
+        let dir = temp_dir("stale");
+        let cache = BankCache::new(&dir);
+        cache
+            .save(Some("PT"), &catalogue(Duration::days(2)))
+            .unwrap();
+
+        let (client, server) = serve(
+            "HTTP/1.1 503 Service Unavailable\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let res = cache.banks(&client, Some("PT")).await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(res.source, CatalogueSource::Stale);
+        assert_eq!(res.catalogue.banks.len(), 1);
+        assert!(matches!(
+            res.error,
+            Some(NordigenError::Http { status: 503, .. })
+        ));
+        // the expired cache is kept as it was.
+        let saved = cache.load(Some("PT")).unwrap().unwrap();
+        assert!(!cache.is_fresh(&saved));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetched() {
+        let dir = temp_dir("fetched");
+        let cache = BankCache::new(&dir);
+        cache
+            .save(Some("PT"), &catalogue(Duration::days(2)))
+            .unwrap();
+
+        let body = serde_json::json!([
+            {
+                "id": "A",
+                "name": "Bank A",
+                "bic": "",
+                "transaction_total_days": "90",
+                "countries": ["PT"],
+                "logo": "",
+            },
+            {
+                "id": "B",
+                "name": "Bank B",
+                "bic": "",
+                "transaction_total_days": "90",
+                "countries": ["PT"],
+                "logo": "",
+            },
+        ])
+        .to_string();
+        let (client, server) = serve(&format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+             ETag: \"v2\"\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ))
+        .await;
+        let res = cache.banks(&client, Some("PT")).await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(res.source, CatalogueSource::Fetched);
+        assert_eq!(res.catalogue.banks.len(), 2);
+        assert_eq!(res.diff.unwrap().added.len(), 1);
+        let saved = cache.load(Some("PT")).unwrap().unwrap();
+        assert_eq!(saved.etag.as_deref(), Some("\"v2\""));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
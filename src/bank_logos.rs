@@ -0,0 +1,420 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures_util::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
+
+use crate::banks::BankEntry;
+use crate::error::NordigenError;
+use crate::store::FileStore;
+
+/// Default number of logos downloaded at once.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+/// Largest logo we are willing to download.
+pub const MAX_LOGO_LEN: usize = 1024 * 1024;
+
+const INDEX_FILE: &str = "index.json";
+
+/// Image formats accepted as logos.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    /// Only the root element is checked: an SVG may still carry scripts, so
+    /// it must only be displayed as an image, e.g. through `<img>`, never
+    /// inlined into a page or opened as a document.
+    Svg,
+}
+
+impl ImageKind {
+    /// Recognize an image by its contents, rather than trusting whatever
+    /// the server claims it to be.
+    pub fn detect(contents: &[u8]) -> Option<ImageKind> {
+        if contents.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageKind::Png)
+        } else if contents.starts_with(b"\xff\xd8\xff") {
+            Some(ImageKind::Jpeg)
+        } else if contents.starts_with(b"GIF87a")
+            || contents.starts_with(b"GIF89a")
+        {
+            Some(ImageKind::Gif)
+        } else if contents.len() >= 12
+            && &contents[..4] == b"RIFF"
+            && &contents[8..12] == b"WEBP"
+        {
+            Some(ImageKind::Webp)
+        } else if is_svg(contents) {
+            Some(ImageKind::Svg)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageKind::Png => "png",
+            ImageKind::Jpeg => "jpg",
+            ImageKind::Gif => "gif",
+            ImageKind::Webp => "webp",
+            ImageKind::Svg => "svg",
+        }
+    }
+}
+
+/// SVG is text, so require its root element to be `<svg`, past only an XML
+/// declaration, comments and a doctype. This tells an SVG from e.g. an HTML
+/// error page; it says nothing about whether the SVG is safe.
+fn is_svg(contents: &[u8]) -> bool {
+    let text = match std::str::from_utf8(contents) {
+        Err(_) => return false,
+        Ok(text) => text.trim_start_matches('\u{feff}'),
+    };
+
+    let mut rest = text;
+    loop {
+        rest = rest.trim_start();
+        let end = if rest.starts_with("<?xml") {
+            rest.find("?>").map(|pos| pos + 2)
+        } else if rest.starts_with("<!--") {
+            rest.find("-->").map(|pos| pos + 3)
+        } else if rest.len() >= 9
+            && rest.is_char_boundary(9)
+            && rest[..9].eq_ignore_ascii_case("<!doctype")
+        {
+            // an internal subset may declare entities; don't go there.
+            match rest.find('>') {
+                Some(pos) if !rest[..pos].contains('[') => Some(pos + 1),
+                _ => return false,
+            }
+        } else {
+            break;
+        };
+        match end {
+            None => return false,
+            Some(end) => rest = &rest[end..],
+        }
+    }
+    let root = match rest.strip_prefix("<svg") {
+        None => return false,
+        Some(root) => root,
+    };
+    root.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/')
+}
+
+/// Downloads institution logos into a directory, where each is named after
+/// the SHA-256 of its contents. Logos already downloaded, by this or an
+/// earlier run, are not fetched again.
+pub struct LogoCache {
+    dir: PathBuf,
+    http: reqwest::Client,
+    concurrency: usize,
+}
+
+impl LogoCache {
+    pub fn new(dir: &Path) -> Result<LogoCache, NordigenError> {
+        // logos are served from a CDN, so this client never carries our
+        // access token.
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        Ok(LogoCache {
+            dir: dir.to_path_buf(),
+            http,
+            concurrency: DEFAULT_CONCURRENCY,
+        })
+    }
+
+    /// `nordigen/logos` under the user's cache directory.
+    pub fn default_dir() -> Option<PathBuf> {
+        Some(dirs::cache_dir()?.join("nordigen").join("logos"))
+    }
+
+    pub fn set_concurrency(&mut self, concurrency: usize) {
+        self.concurrency = concurrency.max(1);
+    }
+
+    fn index(&self) -> FileStore {
+        FileStore::new(&self.dir.join(INDEX_FILE))
+    }
+
+    /// Map of logo URL to file name, for logos already downloaded.
+    fn load_index(&self) -> Result<BTreeMap<String, String>, NordigenError> {
+        match self.index().read_raw()? {
+            None => Ok(BTreeMap::new()),
+            Some(contents) => {
+                Ok(serde_json::from_slice(&contents).unwrap_or_default())
+            }
+        }
+    }
+
+    /// Add `added` to the index on disk, keeping whatever other runs have
+    /// added meanwhile, and return the resulting index.
+    fn add_to_index(
+        &self,
+        added: BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, String>, NordigenError> {
+        let mut index = BTreeMap::new();
+        self.index().update_raw(|contents| {
+            if let Some(contents) = contents {
+                index = serde_json::from_slice(&contents).unwrap_or_default();
+            }
+            index.extend(added);
+            match serde_json::to_vec_pretty(&index) {
+                Err(err) => Err(NordigenError::Logo(format!(
+                    "unable to serialize logo index: {}",
+                    err
+                ))),
+                Ok(res) => Ok(res),
+            }
+        })?;
+        Ok(index)
+    }
+
+    /// Local copy of the logo for each of `banks`, keyed by institution id.
+    /// Failing to obtain one logo does not prevent obtaining the others.
+    pub async fn fetch(
+        &self,
+        banks: &[BankEntry],
+    ) -> Result<BTreeMap<String, Result<PathBuf, NordigenError>>, NordigenError>
+    {
+        fs::create_dir_all(&self.dir)?;
+        let mut index = self.load_index()?;
+
+        // institutions may share a logo; download each one only once.
+        let mut wanted: Vec<&str> = Vec::new();
+        for bank in banks {
+            let url = bank.logo.as_str();
+            if url.is_empty() || wanted.contains(&url) {
+                continue;
+            }
+            let cached = index
+                .get(url)
+                .map(|name| self.dir.join(name).is_file())
+                .unwrap_or(false);
+            if !cached {
+                wanted.push(url);
+            }
+        }
+
+        let downloads: Vec<(&str, Result<String, NordigenError>)> =
+            stream::iter(wanted)
+                .map(|url| async move { (url, self.download(url).await) })
+                .buffer_unordered(self.concurrency)
+                .collect()
+                .await;
+
+        // several institutions may share a failed logo, so keep the reason
+        // rather than the error itself.
+        let mut failed: BTreeMap<&str, String> = BTreeMap::new();
+        let mut added = BTreeMap::new();
+        for (url, res) in downloads {
+            match res {
+                Err(NordigenError::Logo(reason)) => {
+                    failed.insert(url, reason);
+                }
+                Err(err) => {
+                    failed.insert(url, err.to_string());
+                }
+                Ok(name) => {
+                    added.insert(url.to_string(), name);
+                }
+            }
+        }
+        if !added.is_empty() {
+            index = self.add_to_index(added)?;
+        }
+
+        let mut res = BTreeMap::new();
+        for bank in banks {
+            let path = if bank.logo.is_empty() {
+                Err(NordigenError::Logo(format!(
+                    "institution {} has no logo",
+                    bank.id
+                )))
+            } else if let Some(reason) = failed.get(bank.logo.as_str()) {
+                Err(NordigenError::Logo(format!(
+                    "unable to fetch {}: {}",
+                    bank.logo, reason
+                )))
+            } else {
+                match index.get(&bank.logo) {
+                    None => Err(NordigenError::Logo(format!(
+                        "logo for {} missing from cache",
+                        bank.id
+                    ))),
+                    Some(name) => Ok(self.dir.join(name)),
+                }
+            };
+            res.insert(bank.id.clone(), path);
+        }
+        Ok(res)
+    }
+
+    /// Download a logo and store it, returning its file name.
+    async fn download(&self, url: &str) -> Result<String, NordigenError> {
+        let mut resp = self.http.get(url).send().await?;
+        if !resp.status().is_success() {
+            return Err(NordigenError::Http {
+                status: resp.status().as_u16(),
+                error: None,
+                // the CDN's error pages are of no use, and may be large.
+                body: String::new(),
+            });
+        }
+        if let Some(len) = resp.content_length() {
+            if len > MAX_LOGO_LEN as u64 {
+                return Err(NordigenError::Logo(format!(
+                    "too large ({} bytes)",
+                    len
+                )));
+            }
+        }
+
+        let mut contents = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if contents.len() + chunk.len() > MAX_LOGO_LEN {
+                return Err(NordigenError::Logo(String::from("too large")));
+            }
+            contents.extend_from_slice(&chunk);
+        }
+
+        let kind = match ImageKind::detect(&contents) {
+            None => {
+                return Err(NordigenError::Logo(String::from(
+                    "not a supported image",
+                )));
+            }
+            Some(kind) => kind,
+        };
+        let digest: String = Sha256::digest(&contents)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let name = format!("{}.{}", digest, kind.extension());
+
+        let path = self.dir.join(&name);
+        if !path.is_file() {
+            write_atomic(&path, &contents)?;
+        }
+        Ok(name)
+    }
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), NordigenError> {
+    // distinct logo URLs may well serve identical contents, so concurrent
+    // downloads must not share a temporary file.
+    static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = PathBuf::from(tmp);
+
+    let res = (|| -> Result<(), NordigenError> {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detect(text: &str) -> Option<ImageKind> {
+        ImageKind::detect(text.as_bytes())
+    }
+
+    #[test]
+    fn test_detect_binary() {
+        assert_eq!(
+            ImageKind::detect(b"\x89PNG\r\n\x1a\n...."),
+            Some(ImageKind::Png)
+        );
+        assert_eq!(
+            ImageKind::detect(b"\xff\xd8\xff\xe0"),
+            Some(ImageKind::Jpeg)
+        );
+        assert_eq!(ImageKind::detect(b"GIF89a"), Some(ImageKind::Gif));
+        assert_eq!(
+            ImageKind::detect(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some(ImageKind::Webp)
+        );
+        assert_eq!(ImageKind::detect(b"\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn test_detect_svg() {
+        assert_eq!(detect("<svg></svg>"), Some(ImageKind::Svg));
+        assert_eq!(
+            detect(
+                "<?xml version=\"1.0\"?>\n<!-- logo -->\n\
+                 <!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"x\">\n\
+                 <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"10\">\
+                 <path d=\"M0 0\" fill=\"none\"/></svg>"
+            ),
+            Some(ImageKind::Svg)
+        );
+    }
+
+    #[test]
+    fn test_reject_html() {
+        assert_eq!(
+            detect("<!DOCTYPE html><html><body><svg></svg></body></html>"),
+            None
+        );
+        assert_eq!(detect("<html><svg></svg></html>"), None);
+        assert_eq!(detect("<svgfoo></svgfoo>"), None);
+        assert_eq!(detect("<!DOCTYPE svg [<!ENTITY x \"y\">]><svg/>"), None);
+    }
+
+    #[test]
+    fn test_index_keeps_other_entries() {
+        let dir = std::env::temp_dir()
+            .join(format!("nordigen-test-{}-logo-index", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = LogoCache::new(&dir).unwrap();
+        cache
+            .index()
+            .write_raw(b"{\"https://cdn/a.png\": \"a.png\"}")
+            .unwrap();
+
+        let added = BTreeMap::from([(
+            String::from("https://cdn/b.png"),
+            String::from("b.png"),
+        )]);
+        let index = cache.add_to_index(added).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(cache.load_index().unwrap(), index);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::banks::{BankEntry, BankFeature};
+
+/// A local query over a list of institutions, as returned by
+/// `NordigenClient::list_banks()`.
+///
+/// Name matching ignores case, accents and punctuation, and tolerates small
+/// typos; every word in the query must match some word in the name. Results
+/// are ranked best match first.
+#[derive(Clone, Debug, Default)]
+pub struct BankQuery {
+    name: Option<Vec<String>>,
+    bic_prefix: Option<String>,
+    features: Vec<BankFeature>,
+    min_history_days: Option<u32>,
+    countries: Vec<String>,
+}
+
+/// An institution matching a query, along with how well it matched.
+pub struct BankMatch<'a> {
+    pub bank: &'a BankEntry,
+    pub score: u32,
+}
+
+impl BankQuery {
+    pub fn new() -> BankQuery {
+        BankQuery::default()
+    }
+
+    pub fn with_name(mut self, name: &str) -> BankQuery {
+        let words = words(name);
+        self.name = if words.is_empty() { None } else { Some(words) };
+        self
+    }
+
+    pub fn with_bic_prefix(mut self, prefix: &str) -> BankQuery {
+        self.bic_prefix = Some(prefix.trim().to_uppercase());
+        self
+    }
+
+    /// Require support for `feature`. May be given more than once.
+    pub fn with_feature(mut self, feature: BankFeature) -> BankQuery {
+        self.features.push(feature);
+        self
+    }
+
+    pub fn with_min_history_days(mut self, days: u32) -> BankQuery {
+        self.min_history_days = Some(days);
+        self
+    }
+
+    /// Only match institutions in `country`. May be given more than once, to
+    /// match any of them.
+    pub fn with_country(mut self, country: &str) -> BankQuery {
+        self.countries.push(country.trim().to_uppercase());
+        self
+    }
+
+    /// Score `bank` against the query, or `None` if it does not match.
+    pub fn score(&self, bank: &BankEntry) -> Option<u32> {
+        if let Some(prefix) = &self.bic_prefix {
+            if !bank.bic.to_uppercase().starts_with(prefix.as_str()) {
+                return None;
+            }
+        }
+        if !self.countries.is_empty()
+            && !bank
+                .countries
+                .iter()
+                .any(|c| self.countries.contains(&c.to_uppercase()))
+        {
+            return None;
+        }
+        if let Some(min) = self.min_history_days {
+            match bank.history_days() {
+                Some(days) if days >= min => {}
+                _ => return None,
+            }
+        }
+        if !self.features.iter().all(|f| bank.supports(*f)) {
+            return None;
+        }
+
+        match &self.name {
+            None => Some(0),
+            Some(query) => score_name(query, &words(&bank.name)),
+        }
+    }
+
+    /// Matching institutions, best match first. Ties go to the shorter name,
+    /// being the closer match, and then in alphabetical order.
+    pub fn run<'a>(&self, banks: &'a [BankEntry]) -> Vec<BankMatch<'a>> {
+        let mut res: Vec<BankMatch> = banks
+            .iter()
+            .filter_map(|bank| {
+                self.score(bank).map(|score| BankMatch { bank, score })
+            })
+            .collect();
+        res.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then_with(|| a.bank.name.len().cmp(&b.bank.name.len()))
+                .then_with(|| a.bank.name.cmp(&b.bank.name))
+        });
+        res
+    }
+}
+
+fn score_name(query: &[String], name: &[String]) -> Option<u32> {
+    let mut score = 0;
+    for word in query {
+        score += name.iter().filter_map(|n| score_word(word, n)).max()?;
+    }
+    if query == name {
+        score += 10;
+    } else if name.starts_with(query) {
+        score += 5;
+    }
+    Some(score)
+}
+
+fn score_word(query: &str, word: &str) -> Option<u32> {
+    if query == word {
+        Some(4)
+    } else if word.starts_with(query) {
+        Some(3)
+    } else if word.contains(query) {
+        Some(2)
+    } else {
+        let allowed = match query.chars().count() {
+            0..=3 => 0,
+            4..=7 => 1,
+            _ => 2,
+        };
+        if allowed > 0 && edit_distance(query, word) <= allowed {
+            Some(1)
+        } else {
+            None
+        }
+    }
+}
+
+/// Levenshtein distance between two strings, by characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+/// Split `text` into lowercase words, with accents removed and punctuation
+/// dropped.
+fn words(text: &str) -> Vec<String> {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        match fold_accent(c) {
+            Some(s) => folded.push_str(s),
+            None if c.is_alphanumeric() => folded.push(c),
+            None => folded.push(' '),
+        }
+    }
+    folded.split_whitespace().map(String::from).collect()
+}
+
+/// Plain ASCII spelling of common accented Latin letters.
+fn fold_accent(c: char) -> Option<&'static str> {
+    let res = match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' | 'ș' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' | 'ț' => "t",
+        'þ' => "th",
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => {
+            "u"
+        }
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        _ => return None,
+    };
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank(id: &str, name: &str, bic: &str, country: &str) -> BankEntry {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": name,
+            "bic": bic,
+            "transaction_total_days": "540",
+            "countries": [country],
+            "logo": "",
+        }))
+        .unwrap()
+    }
+
+    fn banks() -> Vec<BankEntry> {
+        vec![
+            bank("CGD", "Caixa Geral de Depósitos", "CGDIPTPL", "PT"),
+            bank("CCAM", "Caixa Agrícola", "CCCMPTPL", "PT"),
+            bank("BCP", "Millennium BCP", "BCOMPTPL", "PT"),
+            bank("CAIXA", "CaixaBank", "CAIXESBB", "ES"),
+            bank("SEB", "SEB", "ESSESESS", "SE"),
+        ]
+    }
+
+    fn ids(query: &BankQuery, banks: &[BankEntry]) -> Vec<String> {
+        query.run(banks).iter().map(|m| m.bank.id.clone()).collect()
+    }
+
+    #[test]
+    fn test_ranking() {
+        let banks = banks();
+        let res = ids(&BankQuery::new().with_name("caixa geral"), &banks);
+        assert_eq!(res, vec!["CGD"]);
+
+        let res = ids(&BankQuery::new().with_name("caixa"), &banks);
+        assert_eq!(res[0], "CCAM");
+        assert!(res.contains(&String::from("CGD")));
+        assert!(res.contains(&String::from("CAIXA")));
+        assert_eq!(res.last().unwrap(), "CAIXA");
+    }
+
+    #[test]
+    fn test_accents() {
+        let banks = banks();
+        assert_eq!(
+            ids(&BankQuery::new().with_name("agricola"), &banks),
+            ["CCAM"]
+        );
+        assert_eq!(
+            ids(&BankQuery::new().with_name("DEPOSITOS"), &banks),
+            ["CGD"]
+        );
+
+        let banks = vec![bank("X", "Banco Espirito Santo", "", "PT")];
+        assert_eq!(ids(&BankQuery::new().with_name("espírito"), &banks), ["X"]);
+    }
+
+    #[test]
+    fn test_typos() {
+        let banks = banks();
+        assert_eq!(
+            ids(&BankQuery::new().with_name("milenium"), &banks),
+            ["BCP"]
+        );
+        assert_eq!(
+            ids(&BankQuery::new().with_name("milleniun"), &banks),
+            ["BCP"]
+        );
+        assert!(ids(&BankQuery::new().with_name("mxlxnium"), &banks).is_empty());
+        // short words must match exactly.
+        assert!(ids(&BankQuery::new().with_name("sed"), &banks).is_empty());
+    }
+
+    #[test]
+    fn test_filters() {
+        let banks = banks();
+        assert_eq!(
+            ids(&BankQuery::new().with_bic_prefix("cgd"), &banks),
+            ["CGD"]
+        );
+
+        let query = BankQuery::new().with_country("es").with_country("SE");
+        let mut res = ids(&query, &banks);
+        res.sort();
+        assert_eq!(res, ["CAIXA", "SEB"]);
+
+        let query = BankQuery::new().with_name("caixa").with_country("ES");
+        assert_eq!(ids(&query, &banks), ["CAIXA"]);
+
+        assert!(ids(&BankQuery::new().with_min_history_days(541), &banks)
+            .is_empty());
+        assert_eq!(
+            ids(&BankQuery::new().with_min_history_days(540), &banks).len(),
+            5
+        );
+    }
+}
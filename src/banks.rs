@@ -13,60 +13,125 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use chrono::{DateTime, Days, Utc};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Days, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
 
-use crate::auth_http_cb;
+use crate::auth_http_cb::{self, CallbackListener};
+pub use crate::auth_http_cb::{CallbackConfig, CallbackOutcome, LandingPages};
+use crate::client::NordigenClient;
+use crate::error::NordigenError;
+use crate::requisitions::{Requisition, RequisitionOptions};
+
+pub use tokio_util::sync::CancellationToken;
+
+/// Payment kinds an institution may support.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum PaymentKind {
+    SinglePayment,
+    BulkPayment,
+    PeriodicPayment,
+    #[serde(other)]
+    Unknown,
+}
 
-#[derive(Deserialize)]
+/// Optional features an institution may support.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BankFeature {
+    AccountSelection,
+    BusinessAccounts,
+    CardAccounts,
+    CorporateAccounts,
+    PendingTransactions,
+    PrivateAccounts,
+    SeparateContinuousHistoryConsent,
+    Payments,
+    SubmitPayment,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BankEntry {
     pub id: String,
     pub name: String,
     pub bic: String,
-    pub transaction_total_days: String,
+    #[serde(default, deserialize_with = "deserialize_opt_days")]
+    pub transaction_total_days: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_opt_days")]
+    pub max_access_valid_for_days: Option<u32>,
     pub countries: Vec<String>,
     pub logo: String,
+    /// Payment products available for each kind of payment.
+    #[serde(default)]
+    pub supported_payments: BTreeMap<PaymentKind, Vec<String>>,
+    #[serde(default)]
+    pub supported_features: Vec<BankFeature>,
+    #[serde(default)]
+    pub identification_codes: Vec<String>,
+}
+
+impl BankEntry {
+    /// Days of transaction history the institution makes available, if it
+    /// advertises a valid value.
+    pub fn history_days(&self) -> Option<u32> {
+        self.transaction_total_days
+    }
+
+    pub fn supports(&self, feature: BankFeature) -> bool {
+        self.supported_features.contains(&feature)
+    }
+}
+
+/// The API sends day counts as strings, and not always valid ones; those
+/// that are not a number are taken as absent.
+fn deserialize_opt_days<'de, D>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: Option<serde_json::Value> = Option::deserialize(deserializer)?;
+    Ok(match value {
+        Some(serde_json::Value::Number(n)) => {
+            n.as_u64().and_then(|n| u32::try_from(n).ok())
+        }
+        Some(serde_json::Value::String(s)) => s.trim().parse::<u32>().ok(),
+        _ => None,
+    })
 }
 
 #[derive(Serialize)]
 struct BankRequisitionRequest {
     redirect: String,
     institution_id: String,
+    reference: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    agreement: Option<String>,
     user_language: String,
-}
-
-#[derive(Deserialize)]
-struct BankRequisitionReply {
-    id: String,
-    created: DateTime<Utc>,
-    // redirect: String,
-    // status: String,
-    // agreement: String,
-    // accounts: Vec<String>,
-    // reference: String,
-    // user_language: String,
-    link: String,
-    // ssn: Option<String>,
-    // account_selection: bool,
-    // redirect_immediate: bool,
+    account_selection: bool,
+    redirect_immediate: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssn: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct BankRequisitionState {
     pub requisition_id: String,
     pub created_at: DateTime<Utc>,
+    /// Reference sent with the requisition, and echoed back by the bank.
+    #[serde(default)]
+    pub reference: Option<String>,
 }
 
-#[derive(Deserialize)]
-pub struct BankRequisitionsGetReply {
-    // id: String,
-    // created: String,
-    // institution_id: String,
-    accounts: Vec<String>,
-    // account_selection: bool,
-}
-
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct BankAuthState {
     pub bank_id: String,
     pub requisition: BankRequisitionState,
@@ -149,273 +214,537 @@ pub struct AccountTransactions {
     pub pending: Vec<AccountPendingTransaction>,
 }
 
+/// Date range for a transactions query. Either bound may be left open, in
+/// which case the API's default applies.
+#[derive(Clone, Copy, Default)]
+pub struct DateRange {
+    pub date_from: Option<NaiveDate>,
+    pub date_to: Option<NaiveDate>,
+}
+
+impl DateRange {
+    pub fn new(
+        date_from: Option<NaiveDate>,
+        date_to: Option<NaiveDate>,
+    ) -> DateRange {
+        DateRange { date_from, date_to }
+    }
+
+    /// The last `days` days, up to and including today.
+    pub fn last_days(days: u32) -> DateRange {
+        let today = Utc::now().date_naive();
+        DateRange {
+            date_from: today.checked_sub_days(Days::new(days.into())),
+            date_to: Some(today),
+        }
+    }
+
+    /// Check the range is well formed and, if `history_days` is provided,
+    /// that it does not reach further back than the bank makes available.
+    pub fn validate(
+        &self,
+        history_days: Option<u32>,
+    ) -> Result<(), NordigenError> {
+        let today = Utc::now().date_naive();
+
+        if let (Some(from), Some(to)) = (self.date_from, self.date_to) {
+            if from > to {
+                return Err(NordigenError::InvalidRequest(format!(
+                    "date_from {} is after date_to {}",
+                    from, to
+                )));
+            }
+        }
+
+        let from = match self.date_from {
+            None => return Ok(()),
+            Some(from) => from,
+        };
+        if from > today {
+            return Err(NordigenError::InvalidRequest(format!(
+                "date_from {} is in the future",
+                from
+            )));
+        }
+
+        if let Some(days) = history_days {
+            let oldest = today
+                .checked_sub_days(Days::new(days.into()))
+                .unwrap_or(NaiveDate::MIN);
+            if from < oldest {
+                return Err(NordigenError::InvalidRequest(format!(
+                    "date_from {} is older than the {} days of history \
+                     available (oldest {})",
+                    from, days, oldest
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Deserialize)]
 struct AccountTransactionsReply {
     pub transactions: AccountTransactions,
 }
 
-pub async fn list(
-    token: &String,
-    country: &Option<String>,
-) -> Result<Vec<BankEntry>, String> {
-    let client = reqwest::Client::new();
-    let mut req = client
-        .get("https://ob.nordigen.com/api/v2/institutions/")
-        .header("accept", "application/json")
-        .bearer_auth(token);
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum BalanceType {
+    ClosingBooked,
+    Expected,
+    InterimAvailable,
+    InterimBooked,
+    OpeningBooked,
+    ForwardAvailable,
+    NonInvoiced,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+pub struct BalanceAmount {
+    pub amount: String,
+    pub currency: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Balance {
+    pub balance_amount: BalanceAmount,
+    pub balance_type: BalanceType,
+    pub credit_limit_included: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_opt_datetime")]
+    pub last_change_date_time: Option<DateTime<Utc>>,
+    pub reference_date: Option<NaiveDate>,
+}
+
+pub struct AccountBalances {
+    pub account_id: String,
+    pub balances: Vec<Balance>,
+}
+
+#[derive(Deserialize)]
+struct AccountBalancesReply {
+    pub balances: Vec<Balance>,
+}
+
+/// Banks are not consistent in how they format timestamps: some leave out
+/// the timezone, taken to be UTC, or the time, taken to be midnight UTC.
+/// Being optional, a timestamp that can't be parsed at all is taken as
+/// absent rather than failing the whole reply.
+fn deserialize_opt_datetime<'de, D>(
+    deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    Ok(value.and_then(|v| parse_datetime(v.trim())))
+}
 
-    if let Some(ccode) = country {
-        req = req.query(&[("country", ccode)]);
+fn parse_datetime(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Some(dt.with_timezone(&Utc));
     }
-    let res = match req.send().await {
-        Err(error) => {
-            return Err(format!("Unable to obtain bank list: {}", error));
-        }
-        Ok(res) => res,
-    };
+    // `%z` takes offsets both with and without a colon.
+    if let Ok(dt) = DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f%z") {
+        return Some(dt.with_timezone(&Utc));
+    }
+    if let Ok(dt) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f")
+    {
+        return Some(Utc.from_utc_datetime(&dt));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|dt| Utc.from_utc_datetime(&dt))
+}
 
-    let banks: Vec<BankEntry> = match res.json::<Vec<BankEntry>>().await {
-        Err(error) => {
-            return Err(format!("Unable to parse bank list: {}", error));
+impl NordigenClient {
+    pub async fn list_banks(
+        &self,
+        country: Option<&str>,
+    ) -> Result<Vec<BankEntry>, NordigenError> {
+        self.json::<Vec<BankEntry>>(self.list_banks_request(country))
+            .await
+    }
+
+    /// The request behind `list_banks()`, for callers adding headers to it.
+    pub(crate) fn list_banks_request(
+        &self,
+        country: Option<&str>,
+    ) -> RequestBuilder {
+        let mut req = self.get("institutions/");
+
+        if let Some(ccode) = country {
+            req = req.query(&[("country", ccode)]);
         }
-        Ok(res) => res,
-    };
+        req
+    }
 
-    Ok(banks)
+    /// Look up a single institution, e.g. to check what it supports before
+    /// linking it.
+    pub async fn get_bank(&self, id: &str) -> Result<BankEntry, NordigenError> {
+        self.json::<BankEntry>(self.get(&format!("institutions/{}/", id)))
+            .await
+    }
 }
 
 pub struct Authorize {
-    token: String,
+    client: NordigenClient,
     bank_id: String,
-    requisition: Option<BankRequisitionReply>,
+    bank_name: Option<String>,
+    reference: Option<String>,
+    options: RequisitionOptions,
+    callback: CallbackConfig,
+    listener: Option<CallbackListener>,
+    cancel: CancellationToken,
+    requisition: Option<Requisition>,
 }
 
 impl Authorize {
-    pub fn new(token: &String, bank_id: &String) -> Authorize {
+    pub fn new(client: &NordigenClient, bank_id: &str) -> Authorize {
         Authorize {
-            token: token.clone(),
-            bank_id: bank_id.clone(),
+            client: client.clone(),
+            bank_id: bank_id.to_string(),
+            bank_name: None,
+            reference: None,
+            options: RequisitionOptions::default(),
+            callback: CallbackConfig::default(),
+            listener: None,
+            cancel: CancellationToken::new(),
             requisition: None,
         }
     }
 
-    pub async fn start(self: &mut Self) -> Result<String, String> {
-        let client = reqwest::Client::new();
-        let res = match client
-            .post("https://ob.nordigen.com/api/v2/requisitions/")
-            .header("accept", "application/json")
-            .header("Content-Type", "application/json")
-            .bearer_auth(&self.token)
-            .json(&BankRequisitionRequest {
-                redirect: String::from("http://127.0.0.1:1337"),
-                institution_id: self.bank_id.clone(),
-                user_language: String::from("EN"),
-            })
-            .send()
-            .await
-        {
-            Err(error) => {
-                return Err(format!(
-                    "Error obtaining authorization: {}",
-                    error
-                ));
-            }
-            Ok(res) => res,
-        };
+    /// Token that, once cancelled, makes a pending `wait_callback()` return
+    /// `NordigenError::CallbackCancelled`.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
 
-        let requisition = res
-            .json::<BankRequisitionReply>()
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!("Error obtaining requisition response: {err}");
-                std::process::exit(1);
-            });
+    /// Set the bank's name, as shown on the landing pages; the bank's id is
+    /// shown otherwise.
+    pub fn set_bank_name(&mut self, name: &str) {
+        self.bank_name = Some(name.to_string());
+    }
+
+    /// The requisition, once the flow has been started.
+    pub fn requisition(&self) -> Option<&Requisition> {
+        self.requisition.as_ref()
+    }
+
+    /// Reference for this flow, once started.
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    /// Set the reference, agreement, language and other options for the
+    /// requisition. Validated when the flow starts.
+    pub fn set_options(&mut self, options: RequisitionOptions) {
+        self.options = options;
+    }
+
+    /// Set where to listen for, and where the bank should send, the
+    /// redirect at the end of the flow. Must be called before `start()`.
+    pub fn set_callback(&mut self, callback: CallbackConfig) {
+        self.callback = callback;
+    }
+
+    /// URL the bank will redirect to, once the flow has been started.
+    pub fn redirect_url(&self) -> Option<&str> {
+        self.listener.as_ref().map(|l| l.redirect_url())
+    }
+
+    async fn create_requisition(
+        &mut self,
+        redirect: &str,
+    ) -> Result<String, NordigenError> {
+        self.options.validate()?;
+        let reference = match self.options.reference() {
+            Some(reference) => reference.to_string(),
+            None => random_reference()?,
+        };
+        let requisition = self
+            .client
+            .json::<Requisition>(self.client.post("requisitions/").json(
+                &BankRequisitionRequest {
+                    redirect: redirect.to_string(),
+                    institution_id: self.bank_id.clone(),
+                    reference: reference.clone(),
+                    agreement: self.options.agreement().map(String::from),
+                    user_language: self.options.user_language().to_string(),
+                    account_selection: self.options.account_selection(),
+                    redirect_immediate: self.options.redirect_immediate(),
+                    ssn: self.options.ssn().map(String::from),
+                },
+            ))
+            .await?;
         let link = requisition.link.clone();
         self.requisition = Some(requisition);
+        self.reference = Some(reference);
 
         Ok(link)
     }
 
+    pub async fn start(&mut self) -> Result<String, NordigenError> {
+        let listener = CallbackListener::bind(&self.callback).await?;
+        let link = self.create_requisition(listener.redirect_url()).await?;
+        self.listener = Some(listener);
+
+        Ok(link)
+    }
+
+    /// Start the flow without listening for the bank's redirect, for when
+    /// it cannot reach us, e.g. over SSH. The bank still redirects the user
+    /// to the callback's redirect URL, or the default local one. Follow up
+    /// with `poll_status()`.
+    pub async fn start_headless(&mut self) -> Result<String, NordigenError> {
+        let redirect = self.callback.redirect_url()?;
+        self.create_requisition(&redirect).await
+    }
+
+    /// Poll the requisition every `interval` until it is linked, fails, or
+    /// `deadline` passes. Honours the cancellation token. Transient errors
+    /// are retried, waiting as long as the API asks when rate limited.
+    pub async fn poll_status(
+        &mut self,
+        interval: Duration,
+        deadline: Option<Duration>,
+    ) -> Result<BankRequisitionState, NordigenError> {
+        let req = match &self.requisition {
+            None => {
+                return Err(NordigenError::Callback(String::from(
+                    "Unable to find existing requisition!",
+                )));
+            }
+            Some(res) => res,
+        };
+
+        let poll_loop = async {
+            loop {
+                let reply = match self.client.get_requisition(&req.id).await {
+                    Err(NordigenError::RateLimited {
+                        retry_after: Some(secs),
+                    }) => {
+                        tokio::time::sleep(Duration::from_secs(secs)).await;
+                        continue;
+                    }
+                    Err(err) if err.is_transient() => {
+                        tokio::time::sleep(interval).await;
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                    Ok(res) => res,
+                };
+                if reply.status.is_linked() {
+                    return Ok(());
+                }
+                if reply.status.is_terminal() {
+                    return Err(NordigenError::from_status(reply.status));
+                }
+                tokio::time::sleep(interval).await;
+            }
+        };
+        let timeout = async {
+            match deadline {
+                None => std::future::pending::<()>().await,
+                Some(duration) => tokio::time::sleep(duration).await,
+            }
+        };
+
+        tokio::select! {
+            res = poll_loop => res?,
+            _ = timeout => return Err(NordigenError::CallbackTimeout),
+            _ = self.cancel.cancelled() => {
+                return Err(NordigenError::CallbackCancelled);
+            }
+        };
+
+        Ok(BankRequisitionState {
+            requisition_id: req.id.clone(),
+            created_at: req.created,
+            reference: self.reference.clone(),
+        })
+    }
+
     pub async fn wait_callback(
-        self: &mut Self,
-    ) -> Result<BankRequisitionState, String> {
+        &mut self,
+    ) -> Result<BankRequisitionState, NordigenError> {
         let req = match &self.requisition {
             None => {
-                return Err(String::from(
+                return Err(NordigenError::Callback(String::from(
                     "Unable to find existing requisition!",
-                ));
+                )));
             }
             Some(res) => res,
         };
 
-        let bank_ref = match auth_http_cb::wait_for_response() {
+        let listener = match &self.listener {
+            None => {
+                return Err(NordigenError::Callback(String::from(
+                    "Callback listener not bound!",
+                )));
+            }
+            Some(res) => res,
+        };
+        let reference = match &self.reference {
+            None => {
+                return Err(NordigenError::Callback(String::from(
+                    "Unable to find requisition reference!",
+                )));
+            }
+            Some(res) => res,
+        };
+        let res = auth_http_cb::wait_for_response(
+            listener,
+            reference,
+            self.callback.timeout,
+            &self.cancel,
+        )
+        .await;
+        // we're done with the listener either way; free the port.
+        self.listener = None;
+        let mut pending = res?;
+
+        // the redirect can be forged or stale; trust the requisition's own
+        // status over what the redirect claims.
+        let bank_name = self.bank_name.as_ref().unwrap_or(&self.bank_id);
+        let pages = &self.callback.pages;
+        let reply = match self.client.get_requisition(&req.id).await {
             Err(err) => {
-                return Err(format!(
-                    "Unable to obtain bank's callback: {}",
-                    err
-                ));
+                pending.outcome = CallbackOutcome::InstitutionError {
+                    error: String::from("StatusUnavailable"),
+                    details: Some(err.to_string()),
+                };
+                pending.respond(pages, bank_name, 0).await;
+                return Err(err);
             }
             Ok(res) => res,
         };
+        let status = reply.status;
+
+        // a redirect claiming success for a requisition that is not linked
+        // fails on the requisition's status, not on what was claimed.
+        let claimed = pending.outcome == CallbackOutcome::Approved;
+        if !status.is_linked() && claimed {
+            pending.outcome = CallbackOutcome::InstitutionError {
+                error: String::from("RequisitionNotLinked"),
+                details: Some(format!("requisition is {}", status)),
+            };
+        } else if status.is_linked() {
+            pending.outcome = CallbackOutcome::Approved;
+        }
 
-        if req.id != bank_ref {
-            return Err(format!(
-                "Mismatch between requisition id and bank's callback: {}",
-                bank_ref
-            ));
+        let outcome = pending.outcome.clone();
+        pending
+            .respond(pages, bank_name, reply.accounts.len())
+            .await;
+        if outcome != CallbackOutcome::Approved {
+            if claimed || status.is_terminal() {
+                return Err(NordigenError::from_status(status));
+            }
+            return Err(NordigenError::Authorization(outcome));
         }
 
         Ok(BankRequisitionState {
             requisition_id: req.id.clone(),
             created_at: req.created,
+            reference: self.reference.clone(),
         })
     }
 }
 
+/// An unguessable reference for a requisition, as 32 hex digits.
+fn random_reference() -> Result<String, NordigenError> {
+    let mut buf = [0u8; 16];
+    if let Err(err) = getrandom::getrandom(&mut buf) {
+        return Err(NordigenError::Callback(format!(
+            "Unable to generate requisition reference: {}",
+            err
+        )));
+    }
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
 impl BankAuthState {
     pub fn new(
-        bank_id: &String,
+        bank_id: &str,
         requisition: &BankRequisitionState,
     ) -> BankAuthState {
         BankAuthState {
-            bank_id: bank_id.clone(),
+            bank_id: bank_id.to_string(),
             requisition: requisition.clone(),
         }
     }
 }
 
 pub struct Accounts {
-    token: String,
+    client: NordigenClient,
     requisition_id: String,
+    history_days: Option<u32>,
 }
 
 impl Accounts {
-    pub fn new(token: &String, req_id: &String) -> Accounts {
+    pub fn new(client: &NordigenClient, req_id: &str) -> Accounts {
         Accounts {
-            token: token.clone(),
-            requisition_id: req_id.clone(),
+            client: client.clone(),
+            requisition_id: req_id.to_string(),
+            history_days: None,
         }
     }
 
-    pub async fn list(self: &Self) -> Result<Vec<String>, String> {
-        let client = reqwest::Client::new();
-        let req = match client
-            .get(format!(
-                "https://ob.nordigen.com/api/v2/requisitions/{}/",
-                self.requisition_id
-            ))
-            .header("accept", "application/json")
-            .bearer_auth(&self.token)
-            .send()
-            .await
-        {
-            Err(err) => {
-                return Err(format!("Error listing accounts: {}", err));
-            }
-            Ok(res) => res,
-        };
+    /// Set the days of transaction history the bank makes available, as
+    /// per `BankEntry::history_days()`, so that transaction queries reaching
+    /// further back are rejected without spending a request.
+    pub fn set_history_days(&mut self, days: Option<u32>) {
+        self.history_days = days;
+    }
 
-        let requisition = req
-            .json::<BankRequisitionsGetReply>()
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!("Error obtaining requisition response: {}", err);
-                std::process::exit(1);
-            });
+    pub async fn list(&self) -> Result<Vec<String>, NordigenError> {
+        let requisition =
+            self.client.get_requisition(&self.requisition_id).await?;
 
         Ok(requisition.accounts)
     }
 
     async fn info(
-        self: &Self,
-        account_id: &String,
-    ) -> Result<AccountInfo, String> {
-        let client = reqwest::Client::new();
-        let req = match client
-            .get(format!(
-                "https://ob.nordigen.com/api/v2/accounts/{}/",
-                account_id
-            ))
-            .header("accept", "application/json")
-            .bearer_auth(&self.token)
-            .send()
-            .await
-        {
-            Err(err) => {
-                return Err(format!("Error obtaining account info: {}", err));
-            }
-            Ok(res) => res,
-        };
-
-        let contents = req.json::<AccountInfo>().await.unwrap_or_else(|err| {
-            eprintln!("Error obtaining account info response: {}", err);
-            std::process::exit(1);
-        });
+        &self,
+        account_id: &str,
+    ) -> Result<AccountInfo, NordigenError> {
+        let contents = self
+            .client
+            .json::<AccountInfo>(
+                self.client.get(&format!("accounts/{}/", account_id)),
+            )
+            .await?;
 
         Ok(contents)
     }
 
     async fn details(
-        self: &Self,
-        account_id: &String,
-    ) -> Result<AccountDetails, String> {
-        let client = reqwest::Client::new();
-        let req = match client
-            .get(format!(
-                "https://ob.nordigen.com/api/v2/accounts/{}/details/",
-                account_id
-            ))
-            .header("accept", "application/json")
-            .bearer_auth(&self.token)
-            .send()
-            .await
-        {
-            Err(err) => {
-                return Err(format!(
-                    "Error obtaining account details: {}",
-                    err
-                ));
-            }
-            Ok(res) => res,
-        };
-
-        let contents =
-            req.json::<AccountDetailsReply>()
-                .await
-                .unwrap_or_else(|err| {
-                    eprintln!(
-                        "Error obtaining account details response: {}",
-                        err
-                    );
-                    std::process::exit(1);
-                });
+        &self,
+        account_id: &str,
+    ) -> Result<AccountDetails, NordigenError> {
+        let contents = self
+            .client
+            .json::<AccountDetailsReply>(
+                self.client
+                    .get(&format!("accounts/{}/details/", account_id)),
+            )
+            .await?;
 
         Ok(contents.account)
     }
 
     pub async fn meta(
-        self: &Self,
-        account_id: &String,
-    ) -> Result<AccountMeta, String> {
-        let info = match self.info(account_id).await {
-            Err(err) => {
-                return Err(format!(
-                    "Error obtaining account metadata: {}",
-                    err
-                ));
-            }
-            Ok(res) => res,
-        };
-
-        let details = match self.details(account_id).await {
-            Err(err) => {
-                return Err(format!(
-                    "Error obtaining account metadata: {}",
-                    err
-                ));
-            }
-            Ok(res) => res,
-        };
+        &self,
+        account_id: &str,
+    ) -> Result<AccountMeta, NordigenError> {
+        let info = self.info(account_id).await?;
+        let details = self.details(account_id).await?;
 
         Ok(AccountMeta {
             id: info.id,
@@ -431,93 +760,152 @@ impl Accounts {
         })
     }
 
-    pub async fn meta_all(self: &Self) -> Result<Vec<AccountMeta>, String> {
+    pub async fn meta_all(&self) -> Result<Vec<AccountMeta>, NordigenError> {
         let mut all: Vec<AccountMeta> = Vec::new();
 
-        let acclst = match self.list().await {
-            Err(err) => {
-                return Err(err);
-            }
-            Ok(res) => res,
-        };
-
+        let acclst = self.list().await?;
         for account_id in &acclst {
-            let meta = match self.meta(account_id).await {
-                Err(err) => {
-                    return Err(err);
-                }
-                Ok(res) => res,
-            };
-            all.push(meta);
+            all.push(self.meta(account_id).await?);
         }
 
         Ok(all)
     }
 
     pub async fn transactions(
-        self: &Self,
-        account_id: &String,
-    ) -> Result<AccountTransactions, String> {
-        let now = Utc::now();
-        let then = now.clone().checked_sub_days(Days::new(30)).unwrap();
-
-        let start = then.format("%Y-%m-%d").to_string();
-        let end = now.format("%Y-%m-%d").to_string();
-
-        let client = reqwest::Client::new();
-        let req = match client
-            .get(format!(
-                "https://ob.nordigen.com/api/v2/accounts/{}/transactions/",
-                account_id
-            ))
-            .query(&[("date_from", start), ("date_to", end)])
-            .header("accept", "application/json")
-            .bearer_auth(&self.token)
-            .send()
-            .await
-        {
-            Err(err) => {
-                return Err(format!("Error obtaining transactions: {}", err));
-            }
-            Ok(res) => res,
-        };
+        &self,
+        account_id: &str,
+        range: &DateRange,
+    ) -> Result<AccountTransactions, NordigenError> {
+        range.validate(self.history_days)?;
+
+        let mut req = self
+            .client
+            .get(&format!("accounts/{}/transactions/", account_id));
+        if let Some(from) = range.date_from {
+            req = req
+                .query(&[("date_from", from.format("%Y-%m-%d").to_string())]);
+        }
+        if let Some(to) = range.date_to {
+            req = req.query(&[("date_to", to.format("%Y-%m-%d").to_string())]);
+        }
 
-        let contents = req
-            .json::<AccountTransactionsReply>()
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!(
-                    "Error obtaining transaction details response: {}",
-                    err
-                );
-                std::process::exit(1);
-            });
+        let contents =
+            self.client.json::<AccountTransactionsReply>(req).await?;
         Ok(contents.transactions)
     }
 
-    pub async fn balance(self: &Self, account_id: &String) {
-        let client = reqwest::Client::new();
-        let req = match client
-            .get(format!(
-                "https://ob.nordigen.com/api/v2/accounts/{}/balances/",
-                account_id
-            ))
-            .header("accept", "application/json")
-            .bearer_auth(&self.token)
-            .send()
-            .await
-        {
-            Err(err) => {
-                println!("Error obtaining account balance: {}", err);
-                std::process::exit(1);
-            }
-            Ok(res) => res,
-        };
+    pub async fn balance(
+        &self,
+        account_id: &str,
+    ) -> Result<Vec<Balance>, NordigenError> {
+        let contents = self
+            .client
+            .json::<AccountBalancesReply>(
+                self.client
+                    .get(&format!("accounts/{}/balances/", account_id)),
+            )
+            .await?;
+
+        Ok(contents.balances)
+    }
+
+    pub async fn balances_all(
+        &self,
+    ) -> Result<Vec<AccountBalances>, NordigenError> {
+        let mut all: Vec<AccountBalances> = Vec::new();
+
+        let acclst = self.list().await?;
+        for account_id in &acclst {
+            all.push(AccountBalances {
+                account_id: account_id.clone(),
+                balances: self.balance(account_id).await?,
+            });
+        }
+
+        Ok(all)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_datetime() {
+        let expected = Utc.with_ymd_and_hms(2023, 1, 1, 9, 0, 0).unwrap();
+        for value in [
+            "2023-01-01T09:00:00Z",
+            "2023-01-01T10:00:00+01:00",
+            "2023-01-01T10:00:00+0100",
+            "2023-01-01T09:00:00",
+        ] {
+            assert_eq!(parse_datetime(value), Some(expected), "{}", value);
+        }
+        assert_eq!(
+            parse_datetime("2023-01-01T10:00:00.123+0100"),
+            Some(expected + chrono::Duration::milliseconds(123))
+        );
+        assert_eq!(
+            parse_datetime("2023-01-01"),
+            Some(Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(parse_datetime("yesterday"), None);
+    }
+
+    #[test]
+    fn test_balance_with_odd_timestamp() {
+        let balance: Balance = serde_json::from_value(serde_json::json!({
+            "balanceAmount": {"amount": "1.00", "currency": "EUR"},
+            "balanceType": "expected",
+            "lastChangeDateTime": "not a date",
+        }))
+        .unwrap();
+        assert!(balance.last_change_date_time.is_none());
+    }
+
+    fn days_ago(days: u64) -> NaiveDate {
+        Utc::now().date_naive() - Days::new(days)
+    }
+
+    fn assert_invalid(res: Result<(), NordigenError>) {
+        match res {
+            Err(NordigenError::InvalidRequest(_)) => {}
+            other => panic!("expected an invalid request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_date_range_order() {
+        let range = DateRange::new(Some(days_ago(1)), Some(days_ago(2)));
+        assert_invalid(range.validate(None));
+        let range = DateRange::new(Some(days_ago(2)), Some(days_ago(2)));
+        assert!(range.validate(None).is_ok());
+    }
+
+    #[test]
+    fn test_date_range_future() {
+        let tomorrow = Utc::now().date_naive() + Days::new(1);
+        assert_invalid(DateRange::new(Some(tomorrow), None).validate(None));
+    }
+
+    #[test]
+    fn test_date_range_history() {
+        let range = DateRange::new(Some(days_ago(91)), None);
+        assert_invalid(range.validate(Some(90)));
+        assert!(range.validate(Some(91)).is_ok());
+        assert!(range.validate(None).is_ok());
+
+        // the longest range the bank allows is exactly on the boundary.
+        assert!(DateRange::last_days(90).validate(Some(90)).is_ok());
+        assert_invalid(DateRange::last_days(91).validate(Some(90)));
+    }
 
-        let tmp = req.text().await.unwrap_or_else(|err| {
-            eprintln!("kaboom: {}", err);
-            std::process::exit(1);
-        });
-        println!("{}", tmp);
+    #[test]
+    fn test_date_range_open() {
+        assert!(DateRange::new(None, None).validate(Some(90)).is_ok());
+        let range = DateRange::new(None, Some(days_ago(1000)));
+        assert!(range.validate(Some(90)).is_ok());
+        let range = DateRange::new(Some(days_ago(10)), None);
+        assert!(range.validate(Some(90)).is_ok());
     }
 }
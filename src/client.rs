@@ -0,0 +1,251 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures_util::stream::{self, Stream};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::config::NordigenConfig;
+use crate::error::NordigenError;
+use crate::secret::Secret;
+
+pub const DEFAULT_BASE_URL: &str = "https://ob.nordigen.com/api/v2";
+
+/// A page of results from one of the API's list endpoints.
+#[derive(Deserialize, Debug)]
+pub struct Page<T> {
+    pub count: u64,
+    pub next: Option<String>,
+    pub previous: Option<String>,
+    pub results: Vec<T>,
+}
+
+/// Handle to the Nordigen API.
+///
+/// Owns a pooled HTTP client, so it should be created once and shared.
+/// Cloning is cheap, and clones share both the connection pool and the
+/// current access token.
+#[derive(Clone)]
+pub struct NordigenClient {
+    http: reqwest::Client,
+    base_url: String,
+    token: Arc<RwLock<Option<Secret>>>,
+}
+
+impl NordigenClient {
+    pub fn new() -> Result<NordigenClient, NordigenError> {
+        NordigenClient::with_base_url(DEFAULT_BASE_URL)
+    }
+
+    pub fn with_base_url(
+        base_url: &str,
+    ) -> Result<NordigenClient, NordigenError> {
+        NordigenClient::build(base_url, None)
+    }
+
+    /// Create a client using the base URL and HTTP timeout from `config`.
+    pub fn from_config(
+        config: &NordigenConfig,
+    ) -> Result<NordigenClient, NordigenError> {
+        NordigenClient::build(
+            &config.base_url,
+            config.http_timeout.map(Duration::from_secs),
+        )
+    }
+
+    fn build(
+        base_url: &str,
+        timeout: Option<Duration>,
+    ) -> Result<NordigenClient, NordigenError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/json"),
+        );
+
+        let mut builder = reqwest::Client::builder().default_headers(headers);
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        let http = builder.build()?;
+
+        Ok(NordigenClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: Arc::new(RwLock::new(None)),
+        })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn token(&self) -> Option<Secret> {
+        self.token.read().unwrap().clone()
+    }
+
+    pub fn set_token(&self, token: &Secret) {
+        *self.token.write().unwrap() = Some(token.clone());
+    }
+
+    pub fn clear_token(&self) {
+        *self.token.write().unwrap() = None;
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path.trim_start_matches('/'))
+    }
+
+    fn with_auth(&self, req: RequestBuilder) -> RequestBuilder {
+        match self.token.read().unwrap().as_ref() {
+            None => req,
+            Some(token) => req.bearer_auth(token.expose()),
+        }
+    }
+
+    /// Build a GET request for `path`, relative to the base URL, carrying
+    /// the current access token if there is one.
+    pub(crate) fn get(&self, path: &str) -> RequestBuilder {
+        self.with_auth(self.http.get(self.url(path)))
+    }
+
+    /// Build a GET request for an absolute URL, as found in pagination
+    /// links. Only URLs under the base URL are allowed, so the access token
+    /// is never sent elsewhere.
+    pub(crate) fn get_url(
+        &self,
+        url: &str,
+    ) -> Result<RequestBuilder, NordigenError> {
+        let prefix = format!("{}/", self.base_url);
+        if !url.starts_with(&prefix) {
+            return Err(NordigenError::InvalidRequest(format!(
+                "refusing to follow link outside of API: {}",
+                url
+            )));
+        }
+        Ok(self.with_auth(self.http.get(url)))
+    }
+
+    /// Build a PUT request for `path`, relative to the base URL, carrying
+    /// the current access token if there is one.
+    pub(crate) fn put(&self, path: &str) -> RequestBuilder {
+        self.with_auth(self.http.put(self.url(path)))
+    }
+
+    /// Build a DELETE request for `path`, relative to the base URL,
+    /// carrying the current access token if there is one.
+    pub(crate) fn delete(&self, path: &str) -> RequestBuilder {
+        self.with_auth(self.http.delete(self.url(path)))
+    }
+
+    /// Build a POST request for `path`, relative to the base URL, carrying
+    /// the current access token if there is one.
+    pub(crate) fn post(&self, path: &str) -> RequestBuilder {
+        self.with_auth(self.http.post(self.url(path)))
+    }
+
+    /// Build a POST request for `path` without the access token, for the
+    /// token endpoints themselves.
+    pub(crate) fn post_anonymous(&self, path: &str) -> RequestBuilder {
+        self.http.post(self.url(path))
+    }
+
+    /// Send a request, turning transport failures and non-success statuses
+    /// into errors.
+    pub(crate) async fn send(
+        &self,
+        req: RequestBuilder,
+    ) -> Result<Response, NordigenError> {
+        check(req.send().await?).await
+    }
+
+    /// Send a request and decode its JSON reply.
+    pub(crate) async fn json<T: DeserializeOwned>(
+        &self,
+        req: RequestBuilder,
+    ) -> Result<T, NordigenError> {
+        decode(self.send(req).await?).await
+    }
+
+    /// Like `json()`, for a conditional request: a 304 Not Modified reply is
+    /// `None`. The reply's headers are returned along with its contents, for
+    /// their validators.
+    pub(crate) async fn json_if_modified<T: DeserializeOwned>(
+        &self,
+        req: RequestBuilder,
+    ) -> Result<Option<(T, HeaderMap)>, NordigenError> {
+        let res = req.send().await?;
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let res = check(res).await?;
+        let headers = res.headers().clone();
+        Ok(Some((decode(res).await?, headers)))
+    }
+
+    /// Stream every item of a paginated list endpoint, following `next`
+    /// links as needed.
+    pub(crate) fn paginate<T>(
+        &self,
+        path: &str,
+    ) -> impl Stream<Item = Result<T, NordigenError>>
+    where
+        T: DeserializeOwned + 'static,
+    {
+        let start = (self.clone(), Some(self.url(path)), VecDeque::new());
+        stream::try_unfold(start, |(client, mut next, mut buf)| async move {
+            loop {
+                if let Some(item) = buf.pop_front() {
+                    return Ok(Some((item, (client, next, buf))));
+                }
+                let url = match next {
+                    None => return Ok(None),
+                    Some(url) => url,
+                };
+                let page: Page<T> = client.json(client.get_url(&url)?).await?;
+                buf.extend(page.results);
+                next = page.next;
+            }
+        })
+    }
+}
+
+/// Turn a non-success response into an error.
+async fn check(res: Response) -> Result<Response, NordigenError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    let headers = res.headers().clone();
+    let body = res.text().await?;
+    Err(NordigenError::from_response(status, &headers, &body))
+}
+
+async fn decode<T: DeserializeOwned>(
+    res: Response,
+) -> Result<T, NordigenError> {
+    let body = res.text().await?;
+    match serde_json::from_str::<T>(&body) {
+        Err(source) => Err(NordigenError::Decode { source, body }),
+        Ok(value) => Ok(value),
+    }
+}
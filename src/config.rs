@@ -13,12 +13,216 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize)]
+use serde::Deserialize;
+
+use crate::client::DEFAULT_BASE_URL;
+use crate::error::NordigenError;
+use crate::secret::Secret;
+
+pub const DEFAULT_CALLBACK_ADDR: &str = "127.0.0.1:1337";
+pub const DEFAULT_USER_LANGUAGE: &str = "EN";
+
+const ENV_SECRET_ID: &str = "NORDIGEN_SECRET_ID";
+const ENV_SECRET_KEY: &str = "NORDIGEN_SECRET_KEY";
+const ENV_BASE_URL: &str = "NORDIGEN_BASE_URL";
+const ENV_CALLBACK_ADDR: &str = "NORDIGEN_CALLBACK_ADDR";
+const ENV_USER_LANGUAGE: &str = "NORDIGEN_USER_LANGUAGE";
+const ENV_COUNTRY: &str = "NORDIGEN_COUNTRY";
+const ENV_HTTP_TIMEOUT: &str = "NORDIGEN_HTTP_TIMEOUT";
+
+/// Not `Serialize`: the secret key must never end up written out, and a
+/// config without it could not be read back.
+#[derive(Deserialize, Debug)]
 pub struct NordigenConfig {
     pub secret_id: String,
-    pub secret_key: String,
+    pub secret_key: Secret,
+    #[serde(default = "default_base_url")]
+    pub base_url: String,
+    #[serde(default = "default_callback_addr")]
+    pub callback_addr: SocketAddr,
+    #[serde(default = "default_user_language")]
+    pub user_language: String,
+    #[serde(default)]
+    pub default_country: Option<String>,
+    /// HTTP request timeout, in seconds.
+    #[serde(default)]
+    pub http_timeout: Option<u64>,
+}
+
+fn default_base_url() -> String {
+    String::from(DEFAULT_BASE_URL)
+}
+
+fn default_callback_addr() -> SocketAddr {
+    DEFAULT_CALLBACK_ADDR.parse().unwrap()
+}
+
+fn default_user_language() -> String {
+    String::from(DEFAULT_USER_LANGUAGE)
+}
+
+/// One source of configuration values, any of which may be missing.
+#[derive(Deserialize, Default)]
+struct ConfigLayer {
+    secret_id: Option<String>,
+    secret_key: Option<Secret>,
+    base_url: Option<String>,
+    callback_addr: Option<SocketAddr>,
+    user_language: Option<String>,
+    default_country: Option<String>,
+    http_timeout: Option<u64>,
+}
+
+impl ConfigLayer {
+    fn from_file(path: &Path) -> Result<ConfigLayer, NordigenError> {
+        let contents = match std::fs::read_to_string(path) {
+            Err(err) => {
+                return Err(NordigenError::Config(format!(
+                    "unable to read {}: {}",
+                    path.display(),
+                    err
+                )));
+            }
+            Ok(res) => res,
+        };
+
+        let is_json = path
+            .extension()
+            .map(|ext| ext.eq_ignore_ascii_case("json"))
+            .unwrap_or(false);
+        let res = if is_json {
+            serde_json::from_str::<ConfigLayer>(&contents)
+                .map_err(|err| err.to_string())
+        } else {
+            toml::from_str::<ConfigLayer>(&contents)
+                .map_err(|err| err.to_string())
+        };
+
+        match res {
+            Err(err) => Err(NordigenError::Config(format!(
+                "unable to parse {}: {}",
+                path.display(),
+                err
+            ))),
+            Ok(layer) => Ok(layer),
+        }
+    }
+
+    fn from_env() -> Result<ConfigLayer, NordigenError> {
+        Ok(ConfigLayer {
+            secret_id: env_var(ENV_SECRET_ID)?,
+            secret_key: env_var::<String>(ENV_SECRET_KEY)?.map(Secret::new),
+            base_url: env_var(ENV_BASE_URL)?,
+            callback_addr: env_var(ENV_CALLBACK_ADDR)?,
+            user_language: env_var(ENV_USER_LANGUAGE)?,
+            default_country: env_var(ENV_COUNTRY)?,
+            http_timeout: env_var(ENV_HTTP_TIMEOUT)?,
+        })
+    }
+
+    /// Override our values with those set in `other`.
+    fn merge(&mut self, other: ConfigLayer) {
+        fn set<T>(ours: &mut Option<T>, theirs: Option<T>) {
+            if theirs.is_some() {
+                *ours = theirs;
+            }
+        }
+        set(&mut self.secret_id, other.secret_id);
+        set(&mut self.secret_key, other.secret_key);
+        set(&mut self.base_url, other.base_url);
+        set(&mut self.callback_addr, other.callback_addr);
+        set(&mut self.user_language, other.user_language);
+        set(&mut self.default_country, other.default_country);
+        set(&mut self.http_timeout, other.http_timeout);
+    }
+}
+
+fn env_var<T>(name: &str) -> Result<Option<T>, NordigenError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = match std::env::var(name) {
+        Err(_) => return Ok(None),
+        Ok(v) if v.is_empty() => return Ok(None),
+        Ok(v) => v,
+    };
+    match value.parse::<T>() {
+        Err(err) => Err(NordigenError::Config(format!(
+            "invalid value for {}: {}",
+            name, err
+        ))),
+        Ok(res) => Ok(Some(res)),
+    }
+}
+
+impl NordigenConfig {
+    /// Path to the per-user configuration file, `nordigen/config.toml` in
+    /// the XDG config directory. A `config.json` is used instead if it
+    /// exists and the TOML file does not.
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = dirs::config_dir()?.join("nordigen");
+        let toml = dir.join("config.toml");
+        let json = dir.join("config.json");
+        if !toml.exists() && json.exists() {
+            return Some(json);
+        }
+        Some(toml)
+    }
+
+    /// Load configuration from, in increasing order of precedence: built-in
+    /// defaults, the per-user configuration file, the file at `path` if
+    /// provided, and `NORDIGEN_*` environment variables.
+    pub fn load(path: Option<&Path>) -> Result<NordigenConfig, NordigenError> {
+        let mut layer = ConfigLayer::default();
+        let mut sources: Vec<String> = Vec::new();
+
+        if let Some(default) = NordigenConfig::default_path() {
+            if default.exists() {
+                layer.merge(ConfigLayer::from_file(&default)?);
+            }
+            sources.push(default.display().to_string());
+        }
+        if let Some(path) = path {
+            layer.merge(ConfigLayer::from_file(path)?);
+            sources.push(path.display().to_string());
+        }
+        layer.merge(ConfigLayer::from_env()?);
+
+        let mut missing = |field: &str, env: &str| {
+            sources.push(format!("environment variable {}", env));
+            NordigenError::Config(format!(
+                "{} not set in any of: {}",
+                field,
+                sources.join(", ")
+            ))
+        };
+        let secret_id = match layer.secret_id {
+            None => return Err(missing("secret_id", ENV_SECRET_ID)),
+            Some(v) => v,
+        };
+        let secret_key = match layer.secret_key {
+            None => return Err(missing("secret_key", ENV_SECRET_KEY)),
+            Some(v) => v,
+        };
+
+        Ok(NordigenConfig {
+            secret_id,
+            secret_key,
+            base_url: layer.base_url.unwrap_or_else(default_base_url),
+            callback_addr: layer
+                .callback_addr
+                .unwrap_or_else(default_callback_addr),
+            user_language: layer
+                .user_language
+                .unwrap_or_else(default_user_language),
+            default_country: layer.default_country,
+            http_timeout: layer.http_timeout,
+        })
+    }
 }
 
 impl std::fmt::Display for NordigenConfig {
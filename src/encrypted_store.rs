@@ -0,0 +1,509 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use zeroize::Zeroizing;
+
+use crate::error::NordigenError;
+use crate::store::{FileStore, StateStore, StoredState};
+
+const MAGIC: &[u8; 8] = b"NRDGENC1";
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LEN + NONCE_LEN;
+
+const KDF_KEY_FILE: u8 = 0;
+const KDF_ARGON2ID: u8 = 1;
+
+// The header is only authenticated once the key is derived, so its cost
+// parameters must be bounded before then: 256 MiB, 16 passes, 16 lanes.
+const MAX_M_COST: u32 = 256 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+
+/// Where the state encryption key comes from.
+pub enum StoreKey {
+    /// Derive the key from a passphrase, using Argon2id.
+    Passphrase(Zeroizing<String>),
+    /// Read a raw 32 byte key from a file.
+    KeyFile(PathBuf),
+}
+
+impl StoreKey {
+    pub fn passphrase(passphrase: &str) -> StoreKey {
+        StoreKey::Passphrase(Zeroizing::new(passphrase.to_string()))
+    }
+
+    pub fn key_file(path: &Path) -> StoreKey {
+        StoreKey::KeyFile(path.to_path_buf())
+    }
+
+    /// Create a new key file filled with random bytes, readable only by its
+    /// owner. Fails if the file already exists.
+    pub fn generate_key_file(path: &Path) -> Result<StoreKey, NordigenError> {
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        random(&mut key[..])?;
+
+        let mut opts = fs::OpenOptions::new();
+        opts.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        let mut file = opts.open(path)?;
+        file.write_all(&key[..])?;
+        file.sync_all()?;
+
+        Ok(StoreKey::key_file(path))
+    }
+
+    fn kdf(&self) -> u8 {
+        match self {
+            StoreKey::Passphrase(_) => KDF_ARGON2ID,
+            StoreKey::KeyFile(_) => KDF_KEY_FILE,
+        }
+    }
+
+    fn derive(
+        &self,
+        header: &Header,
+    ) -> Result<Zeroizing<[u8; KEY_LEN]>, NordigenError> {
+        if header.kdf != self.kdf() {
+            return Err(NordigenError::Store(String::from(
+                "state was encrypted with a different kind of key",
+            )));
+        }
+
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        match self {
+            StoreKey::KeyFile(path) => {
+                let contents = Zeroizing::new(fs::read(path)?);
+                if contents.len() != KEY_LEN {
+                    return Err(NordigenError::Store(format!(
+                        "key file {} must hold exactly {} bytes",
+                        path.display(),
+                        KEY_LEN
+                    )));
+                }
+                key.copy_from_slice(&contents);
+            }
+            StoreKey::Passphrase(passphrase) => {
+                let params = match Params::new(
+                    header.m_cost,
+                    header.t_cost,
+                    header.p_cost,
+                    Some(KEY_LEN),
+                ) {
+                    Err(err) => {
+                        return Err(NordigenError::Store(format!(
+                            "invalid key derivation parameters: {}",
+                            err
+                        )));
+                    }
+                    Ok(res) => res,
+                };
+                let argon =
+                    Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+                if let Err(err) = argon.hash_password_into(
+                    passphrase.as_bytes(),
+                    &header.salt,
+                    &mut key[..],
+                ) {
+                    return Err(NordigenError::Store(format!(
+                        "unable to derive key: {}",
+                        err
+                    )));
+                }
+            }
+        }
+        Ok(key)
+    }
+}
+
+struct Header {
+    kdf: u8,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+}
+
+impl Header {
+    fn new(key: &StoreKey) -> Result<Header, NordigenError> {
+        let (m_cost, t_cost, p_cost) = match key {
+            StoreKey::KeyFile(_) => (0, 0, 0),
+            StoreKey::Passphrase(_) => (
+                Params::DEFAULT_M_COST,
+                Params::DEFAULT_T_COST,
+                Params::DEFAULT_P_COST,
+            ),
+        };
+        let mut header = Header {
+            kdf: key.kdf(),
+            m_cost,
+            t_cost,
+            p_cost,
+            salt: [0u8; SALT_LEN],
+            nonce: [0u8; NONCE_LEN],
+        };
+        random(&mut header.salt)?;
+        random(&mut header.nonce)?;
+        Ok(header)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN);
+        out.extend_from_slice(MAGIC);
+        out.push(self.kdf);
+        out.extend_from_slice(&self.m_cost.to_le_bytes());
+        out.extend_from_slice(&self.t_cost.to_le_bytes());
+        out.extend_from_slice(&self.p_cost.to_le_bytes());
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&self.nonce);
+        out
+    }
+
+    fn from_bytes(contents: &[u8]) -> Result<Header, NordigenError> {
+        if contents.len() < HEADER_LEN || &contents[..MAGIC.len()] != MAGIC {
+            return Err(NordigenError::Store(String::from(
+                "state file is not encrypted, or is corrupted",
+            )));
+        }
+        let u32_at = |pos: usize| {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&contents[pos..pos + 4]);
+            u32::from_le_bytes(buf)
+        };
+
+        let mut pos = MAGIC.len();
+        let kdf = contents[pos];
+        pos += 1;
+        let (m_cost, t_cost, p_cost) =
+            (u32_at(pos), u32_at(pos + 4), u32_at(pos + 8));
+        pos += 12;
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&contents[pos..pos + SALT_LEN]);
+        pos += SALT_LEN;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&contents[pos..pos + NONCE_LEN]);
+
+        let valid = match kdf {
+            KDF_KEY_FILE => m_cost == 0 && t_cost == 0 && p_cost == 0,
+            KDF_ARGON2ID => {
+                m_cost <= MAX_M_COST
+                    && t_cost <= MAX_T_COST
+                    && p_cost <= MAX_P_COST
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(NordigenError::Store(String::from(
+                "state file header is corrupted",
+            )));
+        }
+
+        Ok(Header {
+            kdf,
+            m_cost,
+            t_cost,
+            p_cost,
+            salt,
+            nonce,
+        })
+    }
+}
+
+/// Keeps state in a file, encrypted with XChaCha20-Poly1305.
+///
+/// Uses `FileStore` underneath, and thus shares its atomic replacement,
+/// locking and permission guarantees. A fresh salt and nonce are used on
+/// every save.
+pub struct EncryptedFileStore {
+    file: FileStore,
+    key: StoreKey,
+}
+
+impl EncryptedFileStore {
+    pub fn new(path: &Path, key: StoreKey) -> EncryptedFileStore {
+        EncryptedFileStore {
+            file: FileStore::new(path),
+            key,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Re-encrypt the stored state with a new key, which is then used for
+    /// all further operations.
+    pub fn rotate_key(&mut self, key: StoreKey) -> Result<(), NordigenError> {
+        let old = &self.key;
+        self.file.update_raw(|contents| {
+            let state = match contents {
+                None => StoredState::new(),
+                Some(contents) => {
+                    StoredState::from_slice(&decrypt(old, &contents)?)?
+                }
+            };
+            encrypt(&key, &Zeroizing::new(state.to_vec()?))
+        })?;
+        self.key = key;
+        Ok(())
+    }
+
+    /// Encrypt an existing plaintext state, e.g. one kept by a `FileStore`.
+    pub fn import(&self, plain: &dyn StateStore) -> Result<(), NordigenError> {
+        self.save(&plain.load()?)
+    }
+}
+
+fn encrypt(
+    store_key: &StoreKey,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, NordigenError> {
+    let header = Header::new(store_key)?;
+    let key = store_key.derive(&header)?;
+    let cipher = XChaCha20Poly1305::new(key[..].into());
+
+    let mut out = header.to_bytes();
+    let payload = Payload {
+        msg: plaintext,
+        aad: &out,
+    };
+    let ciphertext =
+        match cipher.encrypt(XNonce::from_slice(&header.nonce), payload) {
+            Err(_) => {
+                return Err(NordigenError::Store(String::from(
+                    "unable to encrypt state",
+                )));
+            }
+            Ok(res) => res,
+        };
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+fn decrypt(
+    store_key: &StoreKey,
+    contents: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, NordigenError> {
+    let header = Header::from_bytes(contents)?;
+    let key = store_key.derive(&header)?;
+    let cipher = XChaCha20Poly1305::new(key[..].into());
+
+    let payload = Payload {
+        msg: &contents[HEADER_LEN..],
+        aad: &contents[..HEADER_LEN],
+    };
+    match cipher.decrypt(XNonce::from_slice(&header.nonce), payload) {
+        Err(_) => Err(NordigenError::Store(String::from(
+            "unable to decrypt state: wrong key or corrupted file",
+        ))),
+        Ok(res) => Ok(Zeroizing::new(res)),
+    }
+}
+
+impl StateStore for EncryptedFileStore {
+    fn load(&self) -> Result<StoredState, NordigenError> {
+        match self.file.read_raw()? {
+            None => Ok(StoredState::new()),
+            Some(contents) => {
+                StoredState::from_slice(&decrypt(&self.key, &contents)?)
+            }
+        }
+    }
+
+    fn save(&self, state: &StoredState) -> Result<(), NordigenError> {
+        let plaintext = Zeroizing::new(state.to_vec()?);
+        self.file.write_raw(&encrypt(&self.key, &plaintext)?)
+    }
+}
+
+fn random(buf: &mut [u8]) -> Result<(), NordigenError> {
+    match getrandom::getrandom(buf) {
+        Err(err) => Err(NordigenError::Store(format!(
+            "unable to obtain random bytes: {}",
+            err
+        ))),
+        Ok(()) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secret::Secret;
+    use crate::state::NordigenState;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nordigen-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample_state() -> StoredState {
+        let mut state = StoredState::new();
+        state.tokens = Some(NordigenState::new(
+            Secret::from("access"),
+            86400,
+            Secret::from("refresh"),
+            2592000,
+        ));
+        state
+    }
+
+    fn access_token(state: &StoredState) -> Option<String> {
+        state.tokens.as_ref().map(|t| t.token.expose().to_string())
+    }
+
+    fn assert_store_err<T>(res: Result<T, NordigenError>) {
+        match res {
+            Err(NordigenError::Store(_)) => {}
+            Err(err) => panic!("expected a store error, got {}", err),
+            Ok(_) => panic!("expected a store error"),
+        }
+    }
+
+    #[test]
+    fn test_passphrase_roundtrip() {
+        let dir = temp_dir("passphrase");
+        let path = dir.join("state");
+        let store = EncryptedFileStore::new(&path, StoreKey::passphrase("pw"));
+        store.save(&sample_state()).unwrap();
+
+        let contents = fs::read(&path).unwrap();
+        assert!(contents.starts_with(MAGIC));
+        assert!(!contents.windows(6).any(|w| w == b"access"));
+
+        let store = EncryptedFileStore::new(&path, StoreKey::passphrase("pw"));
+        let state = store.load().unwrap();
+        assert_eq!(access_token(&state), Some(String::from("access")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_key_file_roundtrip() {
+        let dir = temp_dir("key-file");
+        let path = dir.join("state");
+        let key = StoreKey::generate_key_file(&dir.join("key")).unwrap();
+        let store = EncryptedFileStore::new(&path, key);
+        store.save(&sample_state()).unwrap();
+
+        let store = EncryptedFileStore::new(
+            &path,
+            StoreKey::key_file(&dir.join("key")),
+        );
+        let state = store.load().unwrap();
+        assert_eq!(access_token(&state), Some(String::from("access")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let dir = temp_dir("wrong-passphrase");
+        let path = dir.join("state");
+        let store = EncryptedFileStore::new(&path, StoreKey::passphrase("pw"));
+        store.save(&sample_state()).unwrap();
+
+        let store =
+            EncryptedFileStore::new(&path, StoreKey::passphrase("other"));
+        assert_store_err(store.load());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tampered_file() {
+        let dir = temp_dir("tampered");
+        let path = dir.join("state");
+        let key = StoreKey::generate_key_file(&dir.join("key")).unwrap();
+        let store = EncryptedFileStore::new(&path, key);
+        store.save(&sample_state()).unwrap();
+        let contents = fs::read(&path).unwrap();
+
+        // a byte in the salt, in the nonce, and in the ciphertext.
+        for pos in [HEADER_LEN - NONCE_LEN - 1, HEADER_LEN - 1, HEADER_LEN + 1]
+        {
+            let mut tampered = contents.clone();
+            tampered[pos] ^= 0x01;
+            fs::write(&path, &tampered).unwrap();
+            assert_store_err(store.load());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_cost_parameters() {
+        let dir = temp_dir("cost");
+        let path = dir.join("state");
+        let store = EncryptedFileStore::new(&path, StoreKey::passphrase("pw"));
+        store.save(&sample_state()).unwrap();
+        let contents = fs::read(&path).unwrap();
+
+        // m_cost, t_cost and p_cost, each set to u32::MAX in turn.
+        for pos in [9, 13, 17] {
+            let mut tampered = contents.clone();
+            tampered[pos..pos + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            fs::write(&path, &tampered).unwrap();
+            assert_store_err(store.load());
+        }
+
+        // key files use no cost parameters at all.
+        let mut tampered = contents.clone();
+        tampered[MAGIC.len()] = KDF_KEY_FILE;
+        fs::write(&path, &tampered).unwrap();
+        let key = StoreKey::generate_key_file(&dir.join("key")).unwrap();
+        assert_store_err(EncryptedFileStore::new(&path, key).load());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_key() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("state");
+        let mut store =
+            EncryptedFileStore::new(&path, StoreKey::passphrase("old"));
+        store.save(&sample_state()).unwrap();
+
+        let key = StoreKey::generate_key_file(&dir.join("key")).unwrap();
+        store.rotate_key(key).unwrap();
+        let state = store.load().unwrap();
+        assert_eq!(access_token(&state), Some(String::from("access")));
+
+        let old = EncryptedFileStore::new(&path, StoreKey::passphrase("old"));
+        assert_store_err(old.load());
+        let new = EncryptedFileStore::new(
+            &path,
+            StoreKey::key_file(&dir.join("key")),
+        );
+        let state = new.load().unwrap();
+        assert_eq!(access_token(&state), Some(String::from("access")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde_json::Value;
+
+use crate::auth_http_cb::CallbackOutcome;
+use crate::requisitions::RequisitionStatus;
+
+/// Error body returned by the Nordigen API on failed requests.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub summary: Option<String>,
+    pub detail: Option<String>,
+    pub status_code: Option<u16>,
+}
+
+impl ApiError {
+    /// Parse an API error body. Validation errors nest their summary and
+    /// detail per field, in which case `detail` holds the raw JSON.
+    pub fn from_body(body: &str) -> Option<ApiError> {
+        let value: Value = serde_json::from_str(body).ok()?;
+        let obj = value.as_object()?;
+
+        let field = |name: &str| match obj.get(name) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(other) => Some(other.to_string()),
+        };
+        let status_code = obj
+            .get("status_code")
+            .and_then(Value::as_u64)
+            .and_then(|v| u16::try_from(v).ok());
+
+        let summary = field("summary");
+        let detail = field("detail");
+        if summary.is_none() && detail.is_none() {
+            return Some(ApiError {
+                summary: None,
+                detail: Some(value.to_string()),
+                status_code,
+            });
+        }
+
+        Some(ApiError {
+            summary,
+            detail,
+            status_code,
+        })
+    }
+
+    fn mentions(&self, what: &str) -> bool {
+        let hit = |v: &Option<String>| {
+            v.as_ref()
+                .map(|s| s.to_lowercase().contains(what))
+                .unwrap_or(false)
+        };
+        hit(&self.summary) || hit(&self.detail)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.summary, &self.detail) {
+            (Some(summary), Some(detail)) => {
+                write!(f, "{}: {}", summary, detail)
+            }
+            (Some(summary), None) => write!(f, "{}", summary),
+            (None, Some(detail)) => write!(f, "{}", detail),
+            (None, None) => write!(f, "unknown error"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NordigenError {
+    #[error("transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+
+    /// A non-success reply; `body` holds the raw response for diagnostics,
+    /// e.g. when a proxy answered with something other than an API error.
+    #[error("HTTP error {status}{}", describe_api_error(.error, .body))]
+    Http {
+        status: u16,
+        error: Option<ApiError>,
+        body: String,
+    },
+
+    /// The response could not be decoded; `body` holds the raw response
+    /// for diagnostics.
+    #[error("unable to decode response: {source}")]
+    Decode {
+        source: serde_json::Error,
+        body: String,
+    },
+
+    #[error("access token expired or invalid")]
+    TokenExpired,
+
+    #[error("requisition expired or access revoked")]
+    RequisitionExpired,
+
+    /// The requisition did not get linked, and is in the given status.
+    #[error("requisition not linked: it is {0}")]
+    RequisitionFailed(RequisitionStatus),
+
+    #[error("rate limited{}", describe_retry_after(.retry_after))]
+    RateLimited { retry_after: Option<u64> },
+
+    #[error("callback error: {0}")]
+    Callback(String),
+
+    #[error("authorization failed: {0}")]
+    Authorization(CallbackOutcome),
+
+    #[error("timed out waiting for the bank authorization")]
+    CallbackTimeout,
+
+    #[error("cancelled while waiting for the bank authorization")]
+    CallbackCancelled,
+
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("state store error: {0}")]
+    Store(String),
+
+    #[error("configuration error: {0}")]
+    Config(String),
+
+    #[error("logo error: {0}")]
+    Logo(String),
+}
+
+impl NordigenError {
+    /// Whether the request may succeed if retried later: the API could not
+    /// be reached, failed on its side, or asked us to slow down.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            NordigenError::Transport(_) | NordigenError::RateLimited { .. } => {
+                true
+            }
+            NordigenError::Http { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// The error for a requisition that is not linked, being in `status`.
+    pub(crate) fn from_status(status: RequisitionStatus) -> NordigenError {
+        match status {
+            RequisitionStatus::Expired => NordigenError::RequisitionExpired,
+            other => NordigenError::RequisitionFailed(other),
+        }
+    }
+
+    /// Classify a failed HTTP response from its status, headers and body.
+    pub(crate) fn from_response(
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &str,
+    ) -> NordigenError {
+        let error = ApiError::from_body(body);
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return NordigenError::RateLimited {
+                retry_after: retry_after(headers),
+            };
+        }
+
+        if let Some(err) = &error {
+            if status == StatusCode::UNAUTHORIZED && err.mentions("token") {
+                return NordigenError::TokenExpired;
+            }
+            if (status == StatusCode::UNAUTHORIZED
+                || status == StatusCode::FORBIDDEN
+                || status == StatusCode::CONFLICT)
+                && (err.mentions("expired") || err.mentions("revoked"))
+            {
+                return NordigenError::RequisitionExpired;
+            }
+        }
+
+        NordigenError::Http {
+            status: status.as_u16(),
+            error,
+            body: body.to_string(),
+        }
+    }
+}
+
+/// Longest part of an unrecognized error body shown when displaying it.
+const MAX_BODY_DISPLAY_LEN: usize = 200;
+
+fn describe_api_error(error: &Option<ApiError>, body: &str) -> String {
+    match error {
+        Some(err) => format!(": {}", err),
+        None => {
+            let body = body.trim();
+            if body.is_empty() {
+                return String::new();
+            }
+            match body.char_indices().nth(MAX_BODY_DISPLAY_LEN) {
+                None => format!(": {}", body),
+                Some((pos, _)) => format!(": {}...", &body[..pos]),
+            }
+        }
+    }
+}
+
+fn describe_retry_after(retry_after: &Option<u64>) -> String {
+    match retry_after {
+        None => String::new(),
+        Some(secs) => format!(", retry after {}s", secs),
+    }
+}
+
+/// Seconds until the rate limit resets, as advertised by the API.
+fn retry_after(headers: &HeaderMap) -> Option<u64> {
+    [
+        "retry-after",
+        "http_x_ratelimit_account_success_reset",
+        "http_x_ratelimit_reset",
+    ]
+    .iter()
+    .filter_map(|name| headers.get(*name))
+    .filter_map(|v| v.to_str().ok())
+    .find_map(|v| v.trim().parse::<u64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_error_keeps_body() {
+        let body = "<html><body>502 Bad Gateway</body></html>";
+        let err = NordigenError::from_response(
+            StatusCode::BAD_GATEWAY,
+            &HeaderMap::new(),
+            body,
+        );
+        match &err {
+            NordigenError::Http {
+                status: 502,
+                error: None,
+                body: kept,
+            } => assert_eq!(kept, body),
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(err.to_string(), format!("HTTP error 502: {}", body));
+
+        let long = "x".repeat(MAX_BODY_DISPLAY_LEN + 1);
+        let err = NordigenError::from_response(
+            StatusCode::BAD_GATEWAY,
+            &HeaderMap::new(),
+            &long,
+        );
+        assert!(err.to_string().ends_with("x..."));
+    }
+
+    #[test]
+    fn test_requisition_status_error() {
+        assert!(matches!(
+            NordigenError::from_status(RequisitionStatus::Expired),
+            NordigenError::RequisitionExpired
+        ));
+        assert!(matches!(
+            NordigenError::from_status(RequisitionStatus::Rejected),
+            NordigenError::RequisitionFailed(RequisitionStatus::Rejected)
+        ));
+        assert!(matches!(
+            NordigenError::from_status(RequisitionStatus::Suspended),
+            NordigenError::RequisitionFailed(RequisitionStatus::Suspended)
+        ));
+    }
+}
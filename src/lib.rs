@@ -13,8 +13,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod agreements;
 mod auth_http_cb;
 pub mod authorize;
+pub mod bank_cache;
+pub mod bank_logos;
+pub mod bank_search;
 pub mod banks;
+pub mod client;
 pub mod config;
+pub mod encrypted_store;
+pub mod error;
+pub mod requisitions;
+pub mod secret;
 pub mod state;
+pub mod store;
+pub mod token;
+
+pub use client::NordigenClient;
+pub use error::NordigenError;
+
+/// Internals exposed for fuzz targets only.
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzzing {
+    pub use crate::auth_http_cb::{parse_request, CallbackRequest};
+}
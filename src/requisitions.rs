@@ -0,0 +1,341 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Utc};
+use futures_util::stream::Stream;
+use serde::{Deserialize, Serialize};
+
+use crate::client::{NordigenClient, Page};
+use crate::config::{NordigenConfig, DEFAULT_USER_LANGUAGE};
+use crate::error::NordigenError;
+
+/// Where a requisition stands in the linking flow.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequisitionStatus {
+    /// Created, but not yet acted upon.
+    #[serde(rename = "CR")]
+    Created,
+    /// Giving consent at the institution.
+    #[serde(rename = "GC")]
+    GivingConsent,
+    /// Undergoing authentication at the institution.
+    #[serde(rename = "UA")]
+    UndergoingAuthentication,
+    /// Rejected, either by the user or the institution.
+    #[serde(rename = "RJ")]
+    Rejected,
+    /// Selecting accounts.
+    #[serde(rename = "SA")]
+    SelectingAccounts,
+    /// Granting access.
+    #[serde(rename = "GA")]
+    GrantingAccess,
+    /// Linked; accounts may now be accessed.
+    #[serde(rename = "LN")]
+    Linked,
+    /// Suspended due to repeated errors.
+    #[serde(rename = "SU")]
+    Suspended,
+    /// Access has expired; a new requisition is needed.
+    #[serde(rename = "EX")]
+    Expired,
+    #[serde(other)]
+    Unknown,
+}
+
+impl RequisitionStatus {
+    pub fn is_linked(&self) -> bool {
+        *self == RequisitionStatus::Linked
+    }
+
+    /// Whether the requisition will never become linked, or has stopped
+    /// being so.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            RequisitionStatus::Rejected
+                | RequisitionStatus::Suspended
+                | RequisitionStatus::Expired
+        )
+    }
+
+    /// Whether the user is still somewhere in the linking flow.
+    pub fn is_pending(&self) -> bool {
+        !self.is_linked()
+            && !self.is_terminal()
+            && *self != RequisitionStatus::Unknown
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            RequisitionStatus::Created => "CR",
+            RequisitionStatus::GivingConsent => "GC",
+            RequisitionStatus::UndergoingAuthentication => "UA",
+            RequisitionStatus::Rejected => "RJ",
+            RequisitionStatus::SelectingAccounts => "SA",
+            RequisitionStatus::GrantingAccess => "GA",
+            RequisitionStatus::Linked => "LN",
+            RequisitionStatus::Suspended => "SU",
+            RequisitionStatus::Expired => "EX",
+            RequisitionStatus::Unknown => "??",
+        }
+    }
+}
+
+impl std::fmt::Display for RequisitionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let desc = match self {
+            RequisitionStatus::Created => "created",
+            RequisitionStatus::GivingConsent => "giving consent",
+            RequisitionStatus::UndergoingAuthentication => {
+                "undergoing authentication"
+            }
+            RequisitionStatus::Rejected => "rejected",
+            RequisitionStatus::SelectingAccounts => "selecting accounts",
+            RequisitionStatus::GrantingAccess => "granting access",
+            RequisitionStatus::Linked => "linked",
+            RequisitionStatus::Suspended => "suspended",
+            RequisitionStatus::Expired => "expired",
+            RequisitionStatus::Unknown => "unknown",
+        };
+        write!(f, "{} ({})", desc, self.code())
+    }
+}
+
+/// A requisition, i.e. a link between an end user and an institution.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Requisition {
+    pub id: String,
+    pub created: DateTime<Utc>,
+    pub redirect: Option<String>,
+    pub status: RequisitionStatus,
+    pub institution_id: Option<String>,
+    pub agreement: Option<String>,
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    pub reference: Option<String>,
+    pub user_language: Option<String>,
+    pub link: String,
+    pub ssn: Option<String>,
+    #[serde(default)]
+    pub account_selection: bool,
+    #[serde(default)]
+    pub redirect_immediate: bool,
+}
+
+/// ISO 639-1 language codes, sorted.
+const ISO_639_1: &[&str] = &[
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az",
+    "ba", "be", "bg", "bi", "bm", "bn", "bo", "br", "bs", "ca", "ce", "ch",
+    "co", "cr", "cs", "cu", "cv", "cy", "da", "de", "dv", "dz", "ee", "el",
+    "en", "eo", "es", "et", "eu", "fa", "ff", "fi", "fj", "fo", "fr", "fy",
+    "ga", "gd", "gl", "gn", "gu", "gv", "ha", "he", "hi", "ho", "hr", "ht",
+    "hu", "hy", "hz", "ia", "id", "ie", "ig", "ii", "ik", "io", "is", "it",
+    "iu", "ja", "jv", "ka", "kg", "ki", "kj", "kk", "kl", "km", "kn", "ko",
+    "kr", "ks", "ku", "kv", "kw", "ky", "la", "lb", "lg", "li", "ln", "lo",
+    "lt", "lu", "lv", "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms", "mt",
+    "my", "na", "nb", "nd", "ne", "ng", "nl", "nn", "no", "nr", "nv", "ny",
+    "oc", "oj", "om", "or", "os", "pa", "pi", "pl", "ps", "pt", "qu", "rm",
+    "rn", "ro", "ru", "rw", "sa", "sc", "sd", "se", "sg", "si", "sk", "sl",
+    "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw", "ta", "te",
+    "tg", "th", "ti", "tk", "tl", "tn", "to", "tr", "ts", "tt", "tw", "ty",
+    "ug", "uk", "ur", "uz", "ve", "vi", "vo", "wa", "wo", "xh", "yi", "yo",
+    "za", "zh", "zu",
+];
+
+/// Whether `code` is a two letter ISO 639-1 language code, in any case.
+pub fn is_iso639_1(code: &str) -> bool {
+    code.len() == 2
+        && ISO_639_1
+            .binary_search(&code.to_lowercase().as_str())
+            .is_ok()
+}
+
+/// Options for the requisition created when linking a bank.
+#[derive(Clone, Debug)]
+pub struct RequisitionOptions {
+    reference: Option<String>,
+    agreement: Option<String>,
+    user_language: String,
+    account_selection: bool,
+    redirect_immediate: bool,
+    ssn: Option<String>,
+}
+
+impl Default for RequisitionOptions {
+    fn default() -> Self {
+        RequisitionOptions {
+            reference: None,
+            agreement: None,
+            user_language: String::from(DEFAULT_USER_LANGUAGE),
+            account_selection: false,
+            redirect_immediate: false,
+            ssn: None,
+        }
+    }
+}
+
+impl RequisitionOptions {
+    pub fn new() -> RequisitionOptions {
+        RequisitionOptions::default()
+    }
+
+    /// Options using the configured user language.
+    pub fn from_config(config: &NordigenConfig) -> RequisitionOptions {
+        RequisitionOptions::new().with_user_language(&config.user_language)
+    }
+
+    /// Reference sent with the requisition, e.g. to correlate it with an
+    /// internal user id. It must be unique per requisition, and should be
+    /// hard to guess since it is what authenticates the bank's redirect. A
+    /// random one is generated if not set.
+    pub fn with_reference(mut self, reference: &str) -> RequisitionOptions {
+        self.reference = Some(reference.to_string());
+        self
+    }
+
+    /// Use an end user agreement, as created with
+    /// `NordigenClient::create_agreement()`, instead of the default one. It
+    /// must be for the same institution.
+    pub fn with_agreement(mut self, agreement_id: &str) -> RequisitionOptions {
+        self.agreement = Some(agreement_id.to_string());
+        self
+    }
+
+    /// Language for the bank's consent screens, as an ISO 639-1 code.
+    pub fn with_user_language(mut self, code: &str) -> RequisitionOptions {
+        self.user_language = code.to_uppercase();
+        self
+    }
+
+    /// Let the user choose which accounts to share, where the bank
+    /// supports it.
+    pub fn with_account_selection(
+        mut self,
+        enabled: bool,
+    ) -> RequisitionOptions {
+        self.account_selection = enabled;
+        self
+    }
+
+    /// Redirect straight back to us once done, skipping the final
+    /// confirmation screen.
+    pub fn with_redirect_immediate(
+        mut self,
+        enabled: bool,
+    ) -> RequisitionOptions {
+        self.redirect_immediate = enabled;
+        self
+    }
+
+    /// The end user's social security number, which some banks verify
+    /// against the account owner.
+    pub fn with_ssn(mut self, ssn: &str) -> RequisitionOptions {
+        self.ssn = Some(ssn.to_string());
+        self
+    }
+
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    pub fn agreement(&self) -> Option<&str> {
+        self.agreement.as_deref()
+    }
+
+    pub fn user_language(&self) -> &str {
+        &self.user_language
+    }
+
+    pub fn account_selection(&self) -> bool {
+        self.account_selection
+    }
+
+    pub fn redirect_immediate(&self) -> bool {
+        self.redirect_immediate
+    }
+
+    pub fn ssn(&self) -> Option<&str> {
+        self.ssn.as_deref()
+    }
+
+    pub fn validate(&self) -> Result<(), NordigenError> {
+        if !is_iso639_1(&self.user_language) {
+            return Err(NordigenError::InvalidRequest(format!(
+                "'{}' is not an ISO 639-1 language code",
+                self.user_language
+            )));
+        }
+        let non_empty = [
+            ("reference", &self.reference),
+            ("agreement", &self.agreement),
+            ("ssn", &self.ssn),
+        ];
+        for (name, value) in non_empty {
+            if let Some(value) = value {
+                if value.trim().is_empty() {
+                    return Err(NordigenError::InvalidRequest(format!(
+                        "{} must not be empty",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl NordigenClient {
+    /// List one page of requisitions.
+    pub async fn list_requisitions(
+        &self,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Result<Page<Requisition>, NordigenError> {
+        let mut req = self.get("requisitions/");
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+        if let Some(offset) = offset {
+            req = req.query(&[("offset", offset)]);
+        }
+        self.json::<Page<Requisition>>(req).await
+    }
+
+    /// Stream all requisitions, fetching further pages as needed.
+    pub fn all_requisitions(
+        &self,
+    ) -> impl Stream<Item = Result<Requisition, NordigenError>> {
+        self.paginate::<Requisition>("requisitions/")
+    }
+
+    pub async fn get_requisition(
+        &self,
+        id: &str,
+    ) -> Result<Requisition, NordigenError> {
+        self.json::<Requisition>(self.get(&format!("requisitions/{}/", id)))
+            .await
+    }
+
+    /// Delete a requisition, revoking access to its accounts.
+    pub async fn delete_requisition(
+        &self,
+        id: &str,
+    ) -> Result<(), NordigenError> {
+        self.send(self.delete(&format!("requisitions/{}/", id)))
+            .await?;
+        Ok(())
+    }
+}
@@ -16,20 +16,24 @@
 use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::secret::Secret;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NordigenState {
-    pub token: String,
+    #[serde(serialize_with = "Secret::serialize_exposed")]
+    pub token: Secret,
     pub token_expires: u32,
-    pub refresh_token: String,
+    #[serde(serialize_with = "Secret::serialize_exposed")]
+    pub refresh_token: Secret,
     pub refresh_expires: u32,
     pub last_updated: DateTime<Utc>,
 }
 
 impl NordigenState {
     pub fn new(
-        token: String,
+        token: Secret,
         token_expires: u32,
-        refresh_token: String,
+        refresh_token: Secret,
         refresh_expires: u32,
     ) -> NordigenState {
         NordigenState {
@@ -41,6 +45,18 @@ impl NordigenState {
         }
     }
 
+    /// Replace the access token after a refresh. The refresh token keeps
+    /// its original expiry date.
+    pub fn update_token(&mut self, token: Secret, token_expires: u32) {
+        let now = Utc::now();
+        let refresh_left = (self.refresh_expires_on() - now).num_seconds();
+
+        self.token = token;
+        self.token_expires = token_expires;
+        self.refresh_expires = refresh_left.clamp(0, u32::MAX.into()) as u32;
+        self.last_updated = now;
+    }
+
     pub fn token_expires_on(&self) -> DateTime<Utc> {
         self.last_updated
             .checked_add_signed(Duration::seconds(self.token_expires.into()))
@@ -60,4 +76,14 @@ impl NordigenState {
     pub fn is_refresh_expired(&self) -> bool {
         self.refresh_expires_on() < Utc::now()
     }
+
+    /// Whether the access token expires within `margin` from now.
+    pub fn token_expires_within(&self, margin: Duration) -> bool {
+        self.token_expires_on() < Utc::now() + margin
+    }
+
+    /// Whether the refresh token expires within `margin` from now.
+    pub fn refresh_expires_within(&self, margin: Duration) -> bool {
+        self.refresh_expires_on() < Utc::now() + margin
+    }
 }
@@ -0,0 +1,372 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+
+use crate::banks::BankAuthState;
+use crate::error::NordigenError;
+use crate::state::NordigenState;
+
+/// Current version of the stored state schema.
+pub const STATE_VERSION: u32 = 1;
+
+/// Everything worth keeping across runs: the API tokens and the bank links,
+/// keyed by institution id.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredState {
+    pub version: u32,
+    pub tokens: Option<NordigenState>,
+    pub banks: BTreeMap<String, BankAuthState>,
+}
+
+impl StoredState {
+    pub fn new() -> StoredState {
+        StoredState {
+            version: STATE_VERSION,
+            tokens: None,
+            banks: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_bank(&mut self, bank: BankAuthState) {
+        self.banks.insert(bank.bank_id.clone(), bank);
+    }
+
+    pub fn remove_bank(&mut self, bank_id: &str) -> Option<BankAuthState> {
+        self.banks.remove(bank_id)
+    }
+
+    /// Bring a state written by an older version of this crate up to the
+    /// current schema.
+    fn migrate(self) -> Result<StoredState, NordigenError> {
+        if self.version > STATE_VERSION {
+            return Err(NordigenError::Store(format!(
+                "state version {} is newer than supported version {}",
+                self.version, STATE_VERSION
+            )));
+        }
+        Ok(StoredState {
+            version: STATE_VERSION,
+            ..self
+        })
+    }
+
+    pub(crate) fn from_slice(
+        contents: &[u8],
+    ) -> Result<StoredState, NordigenError> {
+        match serde_json::from_slice::<StoredState>(contents) {
+            Err(err) => Err(NordigenError::Store(format!(
+                "unable to parse state: {}",
+                err
+            ))),
+            Ok(state) => state.migrate(),
+        }
+    }
+
+    pub(crate) fn to_vec(&self) -> Result<Vec<u8>, NordigenError> {
+        match serde_json::to_vec_pretty(self) {
+            Err(err) => Err(NordigenError::Store(format!(
+                "unable to serialize state: {}",
+                err
+            ))),
+            Ok(res) => Ok(res),
+        }
+    }
+}
+
+impl Default for StoredState {
+    fn default() -> Self {
+        StoredState::new()
+    }
+}
+
+/// Somewhere to keep `StoredState` across runs.
+pub trait StateStore: Send + Sync {
+    /// Load the stored state, or a fresh one if nothing has been stored yet.
+    fn load(&self) -> Result<StoredState, NordigenError>;
+
+    fn save(&self, state: &StoredState) -> Result<(), NordigenError>;
+}
+
+/// Keeps state in a JSON file, readable only by its owner.
+///
+/// Writes go to a temporary file which is then renamed over the original,
+/// so a crash never leaves a truncated state behind. Concurrent access is
+/// serialized through an advisory lock on a sibling `.lock` file.
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: &Path) -> FileStore {
+        FileStore {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn sibling(&self, ext: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(ext);
+        self.path.with_file_name(name)
+    }
+
+    fn lock(&self, exclusive: bool) -> Result<File, NordigenError> {
+        if let Some(parent) = self.path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let lock = private_options().open(self.sibling(".lock"))?;
+        if exclusive {
+            lock.lock_exclusive()?;
+        } else {
+            lock.lock_shared()?;
+        }
+        Ok(lock)
+    }
+
+    /// Read the raw file contents, or `None` if it does not exist yet.
+    pub(crate) fn read_raw(&self) -> Result<Option<Vec<u8>>, NordigenError> {
+        let lock = self.lock(false)?;
+        let res = self.read_locked();
+        lock.unlock()?;
+        res
+    }
+
+    /// Atomically replace the file contents.
+    pub(crate) fn write_raw(
+        &self,
+        contents: &[u8],
+    ) -> Result<(), NordigenError> {
+        let lock = self.lock(true)?;
+        let res = self.write_locked(contents);
+        lock.unlock()?;
+        res
+    }
+
+    /// Replace the file contents with `update` applied to the current ones,
+    /// holding the exclusive lock throughout so no other write is lost.
+    pub(crate) fn update_raw<F>(&self, update: F) -> Result<(), NordigenError>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> Result<Vec<u8>, NordigenError>,
+    {
+        let lock = self.lock(true)?;
+        let res = self
+            .read_locked()
+            .and_then(update)
+            .and_then(|contents| self.write_locked(&contents));
+        lock.unlock()?;
+        res
+    }
+
+    fn read_locked(&self) -> Result<Option<Vec<u8>>, NordigenError> {
+        match File::open(&self.path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+            Ok(mut file) => {
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents)?;
+                Ok(Some(contents))
+            }
+        }
+    }
+
+    fn write_locked(&self, contents: &[u8]) -> Result<(), NordigenError> {
+        let tmp = self.sibling(".tmp");
+
+        let res = (|| -> Result<(), NordigenError> {
+            let mut file = private_options().truncate(true).open(&tmp)?;
+            // a stale temporary file may predate us, with other permissions.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            }
+            file.write_all(contents)?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            Ok(())
+        })();
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        res
+    }
+}
+
+impl StateStore for FileStore {
+    fn load(&self) -> Result<StoredState, NordigenError> {
+        match self.read_raw()? {
+            None => Ok(StoredState::new()),
+            Some(contents) => StoredState::from_slice(&contents),
+        }
+    }
+
+    fn save(&self, state: &StoredState) -> Result<(), NordigenError> {
+        self.write_raw(&state.to_vec()?)
+    }
+}
+
+/// Keeps state in memory only, e.g. for tests or short-lived tools.
+pub struct MemoryStore {
+    state: Mutex<StoredState>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            state: Mutex::new(StoredState::new()),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::new()
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn load(&self) -> Result<StoredState, NordigenError> {
+        Ok(self.state.lock().unwrap().clone())
+    }
+
+    fn save(&self, state: &StoredState) -> Result<(), NordigenError> {
+        *self.state.lock().unwrap() = state.clone();
+        Ok(())
+    }
+}
+
+/// Options for creating files only their owner may read or write.
+fn private_options() -> OpenOptions {
+    let mut opts = OpenOptions::new();
+    opts.read(true).write(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    opts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::banks::BankRequisitionState;
+    use chrono::Utc;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nordigen-test-{}-store-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample_state() -> StoredState {
+        let mut state = StoredState::new();
+        state.add_bank(BankAuthState::new(
+            "SANDBOXFINANCE_SFIN0000",
+            &BankRequisitionState {
+                requisition_id: String::from("req-1"),
+                created_at: Utc::now(),
+                reference: Some(String::from("ref-1")),
+            },
+        ));
+        state
+    }
+
+    fn assert_roundtrip(store: &dyn StateStore) {
+        store.save(&sample_state()).unwrap();
+        let state = store.load().unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        let bank = &state.banks["SANDBOXFINANCE_SFIN0000"];
+        assert_eq!(bank.requisition.requisition_id, "req-1");
+        assert_eq!(bank.requisition.reference.as_deref(), Some("ref-1"));
+    }
+
+    #[test]
+    fn test_file_roundtrip() {
+        let dir = temp_dir("roundtrip");
+        assert_roundtrip(&FileStore::new(&dir.join("state.json")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_roundtrip() {
+        let store = MemoryStore::new();
+        assert!(store.load().unwrap().banks.is_empty());
+        assert_roundtrip(&store);
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let dir = temp_dir("missing");
+        let state = FileStore::new(&dir.join("state.json")).load().unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert!(state.tokens.is_none());
+        assert!(state.banks.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_newer_version() {
+        let dir = temp_dir("version");
+        let path = dir.join("state.json");
+        let contents = format!(
+            "{{\"version\": {}, \"tokens\": null, \"banks\": {{}}}}",
+            STATE_VERSION + 1
+        );
+        fs::write(&path, contents).unwrap();
+        match FileStore::new(&path).load() {
+            Err(NordigenError::Store(_)) => {}
+            Err(err) => panic!("expected a store error, got {}", err),
+            Ok(_) => panic!("expected a store error"),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("mode");
+        let path = dir.join("state.json");
+        let tmp = dir.join("state.json.tmp");
+        fs::write(&tmp, b"stale").unwrap();
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644)).unwrap();
+
+        FileStore::new(&path).save(&sample_state()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!tmp.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
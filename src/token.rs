@@ -0,0 +1,120 @@
+// ob-nordigen-rs: Nordigen Open Banking API
+// Copyright 2023 Joao Eduardo Luis <joao@abysmo.io>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::Duration;
+use tokio::sync::Mutex;
+
+use crate::client::NordigenClient;
+use crate::config::NordigenConfig;
+use crate::error::NordigenError;
+use crate::secret::Secret;
+use crate::state::NordigenState;
+
+/// Default time before expiry at which tokens are renewed.
+pub const DEFAULT_MARGIN_SECS: i64 = 60;
+
+/// Keeps a valid access token at hand, refreshing it before it expires and
+/// re-authorizing once the refresh token itself has expired.
+///
+/// The manager can be shared across tasks, e.g. behind an `Arc`; concurrent
+/// callers wait on a single renewal rather than each issuing their own.
+pub struct TokenManager {
+    client: NordigenClient,
+    config: NordigenConfig,
+    state: Mutex<Option<NordigenState>>,
+    margin: Duration,
+}
+
+impl TokenManager {
+    pub fn new(
+        client: &NordigenClient,
+        config: NordigenConfig,
+        state: Option<NordigenState>,
+    ) -> TokenManager {
+        TokenManager {
+            client: client.clone(),
+            config,
+            state: Mutex::new(state),
+            margin: Duration::seconds(DEFAULT_MARGIN_SECS),
+        }
+    }
+
+    pub fn set_margin(&mut self, margin: Duration) {
+        self.margin = margin;
+    }
+
+    /// Current token state, e.g. to persist it across runs.
+    pub async fn state(&self) -> Option<NordigenState> {
+        self.state.lock().await.clone()
+    }
+
+    /// Drop the current tokens, forcing a new authorization on the next
+    /// call to `token()`. Useful when the API rejects a token that had not
+    /// yet expired.
+    pub async fn invalidate(&self) {
+        *self.state.lock().await = None;
+        self.client.clear_token();
+    }
+
+    /// Obtain a valid access token, renewing it if needed. The client this
+    /// manager was created with is updated to use it.
+    pub async fn token(&self) -> Result<Secret, NordigenError> {
+        let mut guard = self.state.lock().await;
+
+        let state = match guard.as_ref() {
+            None => self.authorize().await?,
+            Some(state) if !state.token_expires_within(self.margin) => {
+                state.clone()
+            }
+            Some(state) if !state.refresh_expires_within(self.margin) => {
+                self.refresh(state.clone()).await?
+            }
+            Some(_) => self.authorize().await?,
+        };
+
+        let token = state.token.clone();
+        self.client.set_token(&token);
+        *guard = Some(state);
+
+        Ok(token)
+    }
+
+    async fn authorize(&self) -> Result<NordigenState, NordigenError> {
+        let reply = self.client.authorize(&self.config).await?;
+        Ok(NordigenState::new(
+            reply.access,
+            reply.access_expires,
+            reply.refresh,
+            reply.refresh_expires,
+        ))
+    }
+
+    async fn refresh(
+        &self,
+        mut state: NordigenState,
+    ) -> Result<NordigenState, NordigenError> {
+        match self.client.refresh(state.refresh_token.expose()).await {
+            Err(NordigenError::TokenExpired)
+            | Err(NordigenError::Http { status: 401, .. }) => {
+                self.authorize().await
+            }
+            Err(err) => Err(err),
+            Ok((token, expires)) => {
+                state.update_token(token, expires);
+                Ok(state)
+            }
+        }
+    }
+}